@@ -2,108 +2,823 @@ extern crate libpulse_binding as pulse;
 
 use std::{thread,time};
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::{Cell,RefCell};
 use std::sync::mpsc;
-use pulse::mainloop::standard::Mainloop;
+use std::io::{Read,Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use pulse::mainloop::standard::{Mainloop,MainloopInternal};
 use pulse::mainloop::standard::IterateResult;
+use pulse::mainloop::api::{Mainloop as MainloopApi,MainloopInner};
+use pulse::mainloop::events::io::IoEvent;
+use pulse::mainloop::events::io::flags as io_flags;
 use pulse::context::Context;
+use pulse::context::introspect::{SinkInfo,SinkInputInfo,SourceInfo};
 use pulse::callbacks::ListResult;
 use pulse::volume;
 use pulse::context::subscribe::subscription_masks;
+use crate::{i3,pause,config,format,scheduler};
+use crate::format::Value;
+use crate::error::{self,Error};
+#[cfg(feature = "alsa")]
+use crate::mixer;
+use crate::block::{Block,Item};
+
+/// Name of the volume block, the audio thread wakes it up on changes.
+pub const VOLUME_BLOCK: &str = "AudioVolume";
+/// Name of the microphone block, woken up like the volume block.
+pub const MICROPHONE_BLOCK: &str = "Microphone";
+/// Name of the block listing application streams, woken up likewise.
+pub const STREAMS_BLOCK: &str = "AudioStreams";
+
+// waiting for the pulse server to come back starts at the first and doubles
+// up to the second
+const MIN_BACKOFF: time::Duration = time::Duration::from_secs(1);
+const MAX_BACKOFF: time::Duration = time::Duration::from_secs(60);
+// how often the pulse server is looked for while the ALSA mixer stands in
+const PULSE_RETRY: time::Duration = time::Duration::from_secs(10);
 
 pub struct Audio {
-    volume: u8,
-    receiver: mpsc::Receiver<u8>,
+    state: State,
+    receiver: mpsc::Receiver<State>,
+    commands: mpsc::Sender<Command>,
+    // writing to this wakes up the audio thread
+    wakeup: UnixStream,
+}
+
+/// What the bar shows about the sink it follows.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Volume {
+    pub percent: u32,
+    pub mute: bool,
+    pub description: Option<String>,
+}
+
+/// What the bar shows about the default source, and about recording in
+/// general.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Microphone {
+    pub volume: Volume,
+    /// How many applications record right now, from any source but the
+    /// monitors of sinks.
+    pub recordings: u32,
+}
+
+/// A sink input, something that plays sound.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Stream {
+    /// Identifies the stream as long as it lives.
+    pub index: u32,
+    pub application: String,
+    pub percent: u32,
+    pub mute: bool,
+    /// Paused by the application.
+    pub corked: bool,
+    channels: u8,
+}
+
+// Everything the audio thread reports in one go.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub(crate) struct State {
+    /// There is a sound server or mixer to talk to.
+    pub connected: bool,
+    pub volume: Volume,
+    pub microphone: Microphone,
+    pub streams: Vec<Stream>,
+}
+
+pub(crate) enum Command {
+    /// Follow the named sink instead of the server's default one.
+    Pin(Option<String>),
+    SetVolume(u32),
+    /// Changes the volume by some percent, but not beyond the given maximum.
+    StepVolume(i32, u32),
+    ToggleMute,
+    /// Makes the next sink the default and moves all streams over to it.
+    NextSink,
+    ToggleSourceMute,
+    /// Like `StepVolume`, for the stream with the given index.
+    StepStreamVolume(u32, i32, u32),
+}
+
+/// Why a backend stopped without failing.
+#[cfg_attr(not(feature = "alsa"), allow(dead_code))]
+pub(crate) enum Exit {
+    /// The bar is gone, so is the audio thread.
+    BarGone,
+    /// The fallback ran long enough, the pulse server might be back.
+    Retry,
+}
+
+/// Where the volume comes from, set by `audio.backend` in the config.
+#[derive(Clone,Copy,Debug,Default,PartialEq,serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    #[default]
+    Pulse,
+    /// Only available with the `alsa` feature.
+    Alsa,
 }
 
 // as it turns out, i don't need no pulseaudio. all info is in ALSA, alsa
 // provides a more fine-grained event based API
 // https://stackoverflow.com/questions/34936783/watch-for-volume-changes-in-alsa-pulseaudio
 impl Audio {
-    pub fn new() -> Self {
+    /// Keeps one connection to the pulse server and reads the volume again
+    /// whenever the server tells us a sink changed. Every change is announced
+    /// on `events`, so the bar does not have to poll. With the `alsa` feature
+    /// the ALSA mixer is used instead if it was asked for, or stands in
+    /// whenever the pulse server can't be reached until it is back. Without
+    /// a mixer a lost server is reconnected to, waiting longer after every
+    /// failed try.
+    pub fn new(
+        pause: pause::Pause,
+        events: mpsc::Sender<scheduler::Event>,
+        config: &config::AudioConfig,
+    ) -> error::Result<Self> {
         let (tx, rx) = mpsc::channel();
+        let (command_tx, command_rx) = mpsc::channel();
+        let (wakeup, wakeup_receiver) = UnixStream::pair().map_err(|err| {
+            Error::Audio(format!("failed to create the wakeup socket: {}", err))
+        })?;
         let audio = Audio {
             receiver: rx,
-            volume: 0,
+            state: State::default(),
+            commands: command_tx,
+            wakeup,
         };
 
+        let config = config.clone();
+        let mut worker = Worker {
+            pause,
+            events,
+            states: tx,
+            commands: command_rx,
+            wakeup: wakeup_receiver,
+            last_state: None,
+            pinned: None,
+        };
         thread::spawn(move || {
+            if config.backend == Backend::Alsa {
+                match run_alsa(&mut worker, &config, None) {
+                    Ok(_) => return,
+                    Err(err) => error::log(&err),
+                }
+            }
+            let mut backoff = MIN_BACKOFF;
+            // only the first failure of an outage is logged
+            let mut logged = false;
             loop {
-                let mut pulse = Pulse::new()
-                    .expect("failed to connect to pulse server");
-                // pulse.listen();
-                let mut sinks = pulse.introspect()
-                    .expect("failed to introspect");
-                sinks.sort_by(|a, b| b.open.cmp(&a.open));
-                sinks.sort_by(|a, b| b.open.cmp(&a.running));
-
-                let sink = &(sinks[0]);
-                let volume = if sink.mute {
-                    0
-                } else {
-                    sink.volume
+                let err = match Pulse::new() {
+                    Ok(pulse) => {
+                        backoff = MIN_BACKOFF;
+                        logged = false;
+                        match run_pulse(pulse, &mut worker) {
+                            Ok(()) => return,
+                            Err(err) => err,
+                        }
+                    },
+                    Err(err) => err,
                 };
-                tx.send(volume).unwrap();
+                if !logged {
+                    error::log(&err);
+                }
+                // the mixer stands in until the server is back, unless it
+                // already failed as the chosen backend
+                if cfg!(feature = "alsa") && config.backend == Backend::Pulse {
+                    match run_alsa(&mut worker, &config, Some(PULSE_RETRY)) {
+                        Ok(Exit::BarGone) => return,
+                        Ok(Exit::Retry) => {
+                            logged = true;
+                            continue;
+                        },
+                        Err(err) if !logged => error::log(&err),
+                        Err(_) => {},
+                    }
+                }
+                logged = true;
 
-                thread::sleep(time::Duration::new(1, 0));
+                if worker.report(State::default()).is_err() {
+                    return;
+                }
+                worker.pause.wait();
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                // nobody was there to carry them out, pins are kept
+                worker.commands();
             }
         });
 
-        audio
+        Ok(audio)
+    }
+
+    pub fn get_volume(&mut self) -> Volume {
+        self.receive();
+        self.state.volume.clone()
+    }
+
+    /// Whether the volume is known, `false` while the sound server is down.
+    pub fn connected(&mut self) -> bool {
+        self.receive();
+        self.state.connected
+    }
+
+    pub fn get_microphone(&mut self) -> Microphone {
+        self.receive();
+        self.state.microphone.clone()
+    }
+
+    /// Every stream playing to any sink.
+    pub fn get_streams(&mut self) -> Vec<Stream> {
+        self.receive();
+        self.state.streams.clone()
+    }
+
+    /// Raises or lowers the volume of a stream like `step_volume` does.
+    pub fn step_stream_volume(&mut self, index: u32, step: i32, max: u32) {
+        self.command(Command::StepStreamVolume(index, step, max));
+    }
+
+    /// Mutes or unmutes the default source.
+    pub fn toggle_microphone(&mut self) {
+        self.command(Command::ToggleSourceMute);
+    }
+
+    fn receive(&mut self) {
+        for state in self.receiver.try_iter() {
+            self.state = state;
+        }
+    }
+
+    /// Follows the named sink, or the server's default one for `None`.
+    pub fn pin(&mut self, sink: Option<String>) {
+        self.command(Command::Pin(sink));
+    }
+
+    pub fn set_volume(&mut self, percent: u32) {
+        self.command(Command::SetVolume(percent));
     }
 
-    pub fn get_volume(&mut self) -> u8 {
-        for volume in self.receiver.try_iter() {
-            self.volume = volume;
+    /// Raises or lowers the volume by `step` percent, never beyond `max`.
+    pub fn step_volume(&mut self, step: i32, max: u32) {
+        self.command(Command::StepVolume(step, max));
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self.command(Command::ToggleMute);
+    }
+
+    /// Switches to the next sink, playing streams move along.
+    pub fn next_sink(&mut self) {
+        self.command(Command::NextSink);
+    }
+
+    fn command(&mut self, command: Command) {
+        // the audio thread only dies with the bar
+        if self.commands.send(command).is_ok() {
+            let _ = self.wakeup.write(&[0]);
         }
-        self.volume
     }
 }
 
+/// The audio thread's end of `Audio`, handed to whichever backend runs.
+pub(crate) struct Worker {
+    pub pause: pause::Pause,
+    events: mpsc::Sender<scheduler::Event>,
+    states: mpsc::Sender<State>,
+    commands: mpsc::Receiver<Command>,
+    /// Readable whenever a command was sent.
+    pub wakeup: UnixStream,
+    last_state: Option<State>,
+    /// The sink the volume block asked for, it outlives connections.
+    pinned: Option<String>,
+}
+
+impl Worker {
+    /// Commands sent since the last call. Pins are kept in `pinned` instead,
+    /// even while there is no server to carry out the rest.
+    pub fn commands(&mut self) -> Vec<Command> {
+        let mut actions = vec![];
+        for command in self.commands.try_iter() {
+            match command {
+                Command::Pin(sink) => self.pinned = sink,
+                action => actions.push(action),
+            }
+        }
+        actions
+    }
+
+    /// Hands the state to the bar and wakes up the blocks it changed. Fails
+    /// once the bar is gone.
+    pub fn report(&mut self, state: State) -> error::Result<()> {
+        let mut updates = vec![];
+        match &self.last_state {
+            Some(last) if last.connected == state.connected => {
+                if last.volume != state.volume {
+                    updates.push(VOLUME_BLOCK);
+                }
+                if last.microphone != state.microphone {
+                    updates.push(MICROPHONE_BLOCK);
+                }
+                if last.streams != state.streams {
+                    updates.push(STREAMS_BLOCK);
+                }
+            },
+            _ => updates = vec![VOLUME_BLOCK, MICROPHONE_BLOCK, STREAMS_BLOCK],
+        }
+        if updates.is_empty() {
+            return Ok(());
+        }
+
+        self.last_state = Some(state.clone());
+        if self.states.send(state).is_err() {
+            return Err(Error::Disconnected("bar"));
+        }
+        for name in updates {
+            let update = scheduler::Event::Update(name.to_owned());
+            if self.events.send(update).is_err() {
+                return Err(Error::Disconnected("bar"));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Follows the pulse server until the bar is gone, which returns `Ok`. An
+// `Err` means the server went away.
+fn run_pulse(mut pulse: Pulse, worker: &mut Worker) -> error::Result<()> {
+    let changed = pulse.subscribe();
+    let wakeup = worker.wakeup.try_clone()
+        .map_err(|err| Error::Audio(err.to_string()))?;
+    pulse.wake_on(wakeup, Rc::clone(&changed))?;
+    loop {
+        worker.pause.wait();
+        // every action starts from what the previous one left
+        for action in worker.commands() {
+            apply(&mut pulse, action, &mut worker.pinned)?;
+        }
+
+        let state = read_state(&mut pulse, &worker.pinned)?;
+        if worker.report(state).is_err() {
+            return Ok(());
+        }
+
+        // sleep until the server or the bar has news for us
+        while !changed.replace(false) {
+            pulse.wait()?;
+        }
+    }
+}
+
+// An `Err` means there is no usable mixer. With `retry` it gives up after
+// that long, so the pulse server can take over again.
+#[cfg(feature = "alsa")]
+fn run_alsa(
+    worker: &mut Worker,
+    config: &config::AudioConfig,
+    retry: Option<time::Duration>,
+) -> error::Result<Exit> {
+    let control = config.control.as_ref().map(|control| &control[..]);
+    mixer::run(worker, &config.device, control, retry)
+}
+
+#[cfg(not(feature = "alsa"))]
+fn run_alsa(
+    _worker: &mut Worker,
+    _config: &config::AudioConfig,
+    _retry: Option<time::Duration>,
+) -> error::Result<Exit> {
+    Err(Error::Audio("built without the alsa feature".to_owned()))
+}
+
+// The sink the bar shows, either the pinned or the default one.
+fn follow_sink(
+    pulse: &mut Pulse,
+    pinned: &Option<String>,
+) -> error::Result<Option<Sink>> {
+    let name = match pinned {
+        Some(name) => Some(name.clone()),
+        None => pulse.default_sink()?,
+    };
+    match name {
+        Some(name) => pulse.sink(&name),
+        None => Ok(None),
+    }
+}
+
+fn read_state(
+    pulse: &mut Pulse,
+    pinned: &Option<String>,
+) -> error::Result<State> {
+    let volume = match follow_sink(pulse, pinned)? {
+        Some(sink) => Volume {
+            percent: sink.volume,
+            mute: sink.mute,
+            description: sink.description,
+        },
+        None => Volume::default(),
+    };
+    let source = match pulse.default_source()? {
+        Some(name) => pulse.source(&name)?,
+        None => None,
+    };
+    let microphone = Microphone {
+        volume: match source {
+            Some(source) => Volume {
+                percent: source.volume,
+                mute: source.mute,
+                description: source.description,
+            },
+            None => Volume::default(),
+        },
+        // a headset can record while the laptop's microphone is the default
+        recordings: pulse.recordings()?,
+    };
+    let streams = pulse.streams()?;
+    Ok(State { connected: true, volume, microphone, streams })
+}
+
+fn apply(
+    pulse: &mut Pulse,
+    action: Command,
+    pinned: &mut Option<String>,
+) -> error::Result<()> {
+    match action {
+        Command::ToggleSourceMute => {
+            let source = match pulse.default_source()? {
+                Some(name) => pulse.source(&name)?,
+                None => None,
+            };
+            if let Some(source) = source {
+                pulse.set_source_mute(&source.name, !source.mute)?;
+            }
+        },
+        Command::StepStreamVolume(index, step, max) => {
+            let stream = pulse.streams()?
+                .into_iter()
+                .find(|stream| stream.index == index);
+            if let Some(stream) = stream {
+                let target = step_percent(stream.percent, step, max);
+                pulse.set_stream_volume(&stream, target)?;
+            }
+        },
+        action => {
+            if let Some(sink) = follow_sink(pulse, pinned)? {
+                apply_to_sink(pulse, &sink, action, pinned)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+fn apply_to_sink(
+    pulse: &mut Pulse,
+    sink: &Sink,
+    action: Command,
+    pinned: &mut Option<String>,
+) -> error::Result<()> {
+    match action {
+        Command::SetVolume(percent) => pulse.set_volume(sink, percent)?,
+        Command::StepVolume(step, max) => {
+            let target = step_percent(sink.volume, step, max);
+            pulse.set_volume(sink, target)?;
+        },
+        Command::ToggleMute => pulse.set_mute(&sink.name, !sink.mute)?,
+        // `Worker::commands` keeps pins, `apply` handles the rest
+        Command::Pin(_) |
+        Command::ToggleSourceMute |
+        Command::StepStreamVolume(..) => {},
+        Command::NextSink => {
+            let names = pulse.sink_names()?;
+            let next = names
+                .iter()
+                .position(|name| *name == sink.name)
+                .map(|position| (position + 1) % names.len())
+                .unwrap_or(0);
+            if let Some(next_name) = names.get(next) {
+                pulse.set_default_sink(next_name)?;
+                pulse.move_sink_inputs(next_name)?;
+                if pinned.is_some() {
+                    *pinned = Some(next_name.clone());
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+// Goes `step` percent up or down, but not below 0 or beyond `max`. Pulse
+// distorts beyond its UI maximum, so `max` never goes further.
+fn step_percent(percent: u32, step: i32, max: u32) -> u32 {
+    let max = max.min(percent_of(volume::Volume::ui_max()));
+    (i64::from(percent) + i64::from(step))
+        .max(0)
+        .min(i64::from(max)) as u32
+}
+
+/// Clicking mutes, scrolling changes the volume, the middle button resets
+/// it to 100%, or to `max` if that is lower, and the right one switches to
+/// the next sink.
+pub struct VolumeBlock {
+    audio: Rc<RefCell<Audio>>,
+    interval: time::Duration,
+    format: String,
+    step: u32,
+    max: u32,
+}
+
+impl VolumeBlock {
+    /// Shows the sink `Audio::pin` asked for, otherwise the server's default
+    /// sink, following it when it changes.
+    pub fn new(
+        audio: Rc<RefCell<Audio>>,
+        interval: Option<time::Duration>,
+        format: Option<String>,
+        step: u32,
+        max: u32,
+    ) -> Self {
+        VolumeBlock {
+            audio,
+            // the audio thread wakes us up on changes, this is a fallback
+            interval: interval.unwrap_or(time::Duration::from_secs(30)),
+            format: format.unwrap_or_else(|| "aud {volume} %".to_owned()),
+            step,
+            max,
+        }
+    }
+}
+
+impl Block for VolumeBlock {
+    fn name(&self) -> &str { VOLUME_BLOCK }
+
+    fn interval(&self) -> time::Duration { self.interval }
+
+    fn update(&mut self) -> error::Result<Item> {
+        let mut audio = self.audio.borrow_mut();
+        if !audio.connected() {
+            return Ok(disconnected(self.name(), "aud --"));
+        }
+        let volume = audio.get_volume();
+        let percent = if volume.mute { 0 } else { volume.percent };
+        Ok(Item {
+            name: self.name().to_owned(),
+            text: format::render(&self.format, &[
+                ("volume", Value::whole(percent as f64)),
+                ("description", Value::from(volume.description.clone())),
+            ]),
+            // without a description i3bar falls back to the full text
+            short_text: volume.description.clone(),
+            good: None,
+            ..Default::default()
+        })
+    }
+
+    fn click(&mut self, event: &i3::ClickEvent) -> error::Result<()> {
+        let mut audio = self.audio.borrow_mut();
+        let step = self.step as i32;
+        match event.button {
+            i3::BUTTON_LEFT => audio.toggle_mute(),
+            i3::BUTTON_MIDDLE => audio.set_volume(100.min(self.max)),
+            i3::BUTTON_RIGHT => audio.next_sink(),
+            i3::SCROLL_UP => audio.step_volume(step, self.max),
+            i3::SCROLL_DOWN => audio.step_volume(-step, self.max),
+            _ => {},
+        }
+        Ok(())
+    }
+}
+
+/// Shows the default source. It turns red while the microphone is live, and
+/// `{recording}` tells whether some application is listening to any
+/// microphone, `{recordings}` how many are. Clicking it toggles the mute.
+pub struct MicrophoneBlock {
+    audio: Rc<RefCell<Audio>>,
+    interval: time::Duration,
+    format: String,
+}
+
+impl MicrophoneBlock {
+    pub fn new(
+        audio: Rc<RefCell<Audio>>,
+        interval: Option<time::Duration>,
+        format: Option<String>,
+    ) -> Self {
+        MicrophoneBlock {
+            audio,
+            // the audio thread wakes us up on changes, this is a fallback
+            interval: interval.unwrap_or(time::Duration::from_secs(30)),
+            format: format
+                .unwrap_or_else(|| "mic {volume} %{recording}".to_owned()),
+        }
+    }
+}
+
+impl Block for MicrophoneBlock {
+    fn name(&self) -> &str { MICROPHONE_BLOCK }
+
+    fn interval(&self) -> time::Duration { self.interval }
+
+    fn update(&mut self) -> error::Result<Item> {
+        let mut audio = self.audio.borrow_mut();
+        if !audio.connected() {
+            return Ok(disconnected(self.name(), "mic --"));
+        }
+        let microphone = audio.get_microphone();
+        let volume = microphone.volume;
+        let percent = if volume.mute { 0 } else { volume.percent };
+        let recording = if microphone.recordings > 0 { " \u{25cf}" } else { "" };
+        Ok(Item {
+            name: self.name().to_owned(),
+            text: format::render(&self.format, &[
+                ("volume", Value::whole(percent as f64)),
+                ("recording", recording.into()),
+                ("recordings", Value::whole(microphone.recordings as f64)),
+                ("description", Value::from(volume.description.clone())),
+            ]),
+            // without a description i3bar falls back to the full text
+            short_text: volume.description.clone(),
+            // a live microphone is what we want to notice
+            good: if volume.mute { None } else { Some(false) },
+            ..Default::default()
+        })
+    }
+
+    fn click(&mut self, event: &i3::ClickEvent) -> error::Result<()> {
+        if event.button == i3::BUTTON_LEFT {
+            self.audio.borrow_mut().toggle_microphone();
+        }
+        Ok(())
+    }
+}
+
+/// Shows one of the streams playing right now, they are green unless paused
+/// or muted. Clicking switches to the next stream, scrolling changes the
+/// volume of the one shown. There is nothing to see while nothing plays.
+pub struct StreamsBlock {
+    audio: Rc<RefCell<Audio>>,
+    interval: time::Duration,
+    format: String,
+    step: u32,
+    max: u32,
+    // index of the stream shown, it might have gone away since
+    selected: Option<u32>,
+}
+
+impl StreamsBlock {
+    pub fn new(
+        audio: Rc<RefCell<Audio>>,
+        interval: Option<time::Duration>,
+        format: Option<String>,
+        step: u32,
+        max: u32,
+    ) -> Self {
+        StreamsBlock {
+            audio,
+            // the audio thread wakes us up on changes, this is a fallback
+            interval: interval.unwrap_or(time::Duration::from_secs(30)),
+            format: format.unwrap_or_else(|| {
+                "{position}/{count} {application} {volume} %{corked}".to_owned()
+            }),
+            step,
+            max,
+            selected: None,
+        }
+    }
+
+    // The stream shown and where it is in the list, the first one if the
+    // selected stream is gone.
+    fn current(&self, streams: &[Stream]) -> Option<usize> {
+        if streams.is_empty() {
+            return None;
+        }
+        let position = self.selected
+            .and_then(|index| {
+                streams.iter().position(|stream| stream.index == index)
+            })
+            .unwrap_or(0);
+        Some(position)
+    }
+}
+
+impl Block for StreamsBlock {
+    fn name(&self) -> &str { STREAMS_BLOCK }
+
+    fn interval(&self) -> time::Duration { self.interval }
+
+    fn update(&mut self) -> error::Result<Item> {
+        let mut audio = self.audio.borrow_mut();
+        if !audio.connected() {
+            return Ok(disconnected(self.name(), "app --"));
+        }
+        let streams = audio.get_streams();
+        let position = match self.current(&streams) {
+            Some(position) => position,
+            // empty blocks are hidden
+            None => return Ok(Item {
+                name: self.name().to_owned(),
+                ..Default::default()
+            }),
+        };
+        let stream = &streams[position];
+        self.selected = Some(stream.index);
+
+        let percent = if stream.mute { 0 } else { stream.percent };
+        let corked = if stream.corked { " paused" } else { "" };
+        Ok(Item {
+            name: self.name().to_owned(),
+            text: format::render(&self.format, &[
+                ("position", Value::whole((position + 1) as f64)),
+                ("count", Value::whole(streams.len() as f64)),
+                ("application", stream.application.clone().into()),
+                ("volume", Value::whole(percent as f64)),
+                ("corked", corked.into()),
+            ]),
+            short_text: Some(stream.application.clone()),
+            good: if stream.corked || stream.mute { None } else { Some(true) },
+            ..Default::default()
+        })
+    }
+
+    fn click(&mut self, event: &i3::ClickEvent) -> error::Result<()> {
+        let mut audio = self.audio.borrow_mut();
+        let streams = audio.get_streams();
+        let position = match self.current(&streams) {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+        let index = streams[position].index;
+        let step = self.step as i32;
+        match event.button {
+            i3::BUTTON_LEFT => {
+                let next = &streams[(position + 1) % streams.len()];
+                self.selected = Some(next.index);
+            },
+            i3::SCROLL_UP => audio.step_stream_volume(index, step, self.max),
+            i3::SCROLL_DOWN => audio.step_stream_volume(index, -step, self.max),
+            _ => {},
+        }
+        Ok(())
+    }
+}
+
+// Shown while there is no sound server.
+fn disconnected(name: &str, text: &str) -> Item {
+    Item {
+        name: name.to_owned(),
+        text: text.to_owned(),
+        good: Some(false),
+        ..Default::default()
+    }
+}
+
+// The mainloop has to outlive what uses it, `drop` takes care of the io event
+// and the context first, whatever order the fields are in.
 struct Pulse {
+    wakeup: Option<IoEvent<MainloopInner<MainloopInternal>>>,
     context: Context,
     mainloop: Mainloop,
 }
 
+impl Drop for Pulse {
+    fn drop(&mut self) {
+        self.wakeup = None;
+        self.context.disconnect();
+    }
+}
+
 impl Pulse {
-     fn new() -> Result<Self, ()> {
+     fn new() -> error::Result<Self> {
         match Mainloop::new() {
             Some(mainloop) => {
-                match Context::new(&mainloop, &"mybar") {
+                match Context::new(&mainloop, "mybar") {
                     Some(context) => {
-                        let mut pulse = Pulse { context, mainloop };
+                        let mut pulse = Pulse {
+                            context,
+                            mainloop,
+                            wakeup: None,
+                        };
                         pulse.connect()?;
                         Ok(pulse)
                     },
-                    None => Err(()),
+                    None => Err(pulse_error("failed to create a context")),
                 }
             },
-            None => Err(())
+            None => Err(pulse_error("failed to create a mainloop"))
         }
      }
 
-     fn iterate(&mut self) -> Result<(), ()> {
-        match self.mainloop.iterate(false) {
-            IterateResult::Quit(_) |
-            IterateResult::Err(_) => {
-                return Err(())
-            },
-            IterateResult::Success(_) => Ok(()),
-        }
-     }
-
-     fn connect(&mut self) -> Result<(), ()> {
+     fn connect(&mut self) -> error::Result<()> {
         let connect_res = self.context.connect(None, pulse::context::flags::NOFLAGS, None);
-        if connect_res.is_err() { return Err(()); }
+        if connect_res.is_err() { return Err(pulse_error("failed to connect")); }
 
         loop {
-            self.iterate()?;
+            self.wait()?;
 
             match self.context.get_state() {
                 pulse::context::State::Ready => { break; }
                 pulse::context::State::Terminated |
                 pulse::context::State::Failed => {
-                    return Err(())
+                    return Err(pulse_error("failed to connect"))
                 },
                 _ => {},
             }
@@ -112,71 +827,473 @@ impl Pulse {
         Ok(())
      }
 
-     fn introspect(&mut self) -> Result<Vec<Sink>, ()> {
-         // probably need to allocate this in the heap so that it has a static
-         // lifetime that way we can then drop it after `ListResult::End` has
-         // been received.
-         let sink_vec = Rc::new(RefCell::new(vec![] as Vec<Sink>));
-         let done = Rc::new(RefCell::new(false));
+     /// Name of the sink the server plays to by default.
+     fn default_sink(&mut self) -> error::Result<Option<String>> {
+         self.server_defaults().map(|(sink, _)| sink)
+     }
+
+     /// Name of the source the server records from by default.
+     fn default_source(&mut self) -> error::Result<Option<String>> {
+         self.server_defaults().map(|(_, source)| source)
+     }
+
+     // The default sink and source names.
+     fn server_defaults(
+         &mut self,
+     ) -> error::Result<(Option<String>, Option<String>)> {
+         let names = Rc::new(RefCell::new((None, None)));
+         let done = Rc::new(Cell::new(false));
          let cb_done = Rc::clone(&done);
-         let cb_sink_vec = Rc::clone(&sink_vec);
-         self.context.introspect().get_sink_info_list(
+         let cb_names = Rc::clone(&names);
+         self.context.introspect().get_server_info(move |info| {
+             *cb_names.borrow_mut() = (
+                 info.default_sink_name
+                     .as_ref()
+                     .map(|name| name.to_string()),
+                 info.default_source_name
+                     .as_ref()
+                     .map(|name| name.to_string()),
+             );
+             cb_done.set(true);
+         });
+         self.run_until(&done)?;
+         let names = names.replace((None, None));
+         Ok(names)
+     }
+
+     /// `None` if there is no source by that name.
+     fn source(&mut self, name: &str) -> error::Result<Option<Source>> {
+         let found = Rc::new(RefCell::new(None));
+         let done = Rc::new(Cell::new(false));
+         let cb_done = Rc::clone(&done);
+         let cb_found = Rc::clone(&found);
+         self.context.introspect().get_source_info_by_name(
+             name,
+             move |list_result| {
+                 match list_result {
+                     ListResult::Item(source) => {
+                         *cb_found.borrow_mut() = Some(Source::from(source));
+                     },
+                     ListResult::End |
+                     ListResult::Error => cb_done.set(true),
+                 }
+             }
+         );
+         self.run_until(&done)?;
+         let source = found.borrow_mut().take();
+         Ok(source)
+     }
+
+     /// How many source outputs are running, leaving out those that record
+     /// what a sink plays, e.g. level meters.
+     fn recordings(&mut self) -> error::Result<u32> {
+         let monitors = self.monitors()?;
+         let recordings = Rc::new(Cell::new(0));
+         let done = Rc::new(Cell::new(false));
+         let cb_done = Rc::clone(&done);
+         let cb_recordings = Rc::clone(&recordings);
+         self.context.introspect().get_source_output_info_list(
+             move |list_result| {
+                 match list_result {
+                     ListResult::Item(output) => {
+                         if !output.corked && !monitors.contains(&output.source) {
+                             cb_recordings.set(cb_recordings.get() + 1);
+                         }
+                     },
+                     ListResult::End |
+                     ListResult::Error => cb_done.set(true),
+                 }
+             }
+         );
+         self.run_until(&done)?;
+         Ok(recordings.get())
+     }
+
+     /// Indices of the sources that are monitors of sinks.
+     fn monitors(&mut self) -> error::Result<Vec<u32>> {
+         let monitors = Rc::new(RefCell::new(vec![]));
+         let done = Rc::new(Cell::new(false));
+         let cb_done = Rc::clone(&done);
+         let cb_monitors = Rc::clone(&monitors);
+         self.context.introspect().get_source_info_list(
+             move |list_result| {
+                 match list_result {
+                     ListResult::Item(source) => {
+                         if source.monitor_of_sink.is_some() {
+                             cb_monitors.borrow_mut().push(source.index);
+                         }
+                     },
+                     ListResult::End |
+                     ListResult::Error => cb_done.set(true),
+                 }
+             }
+         );
+         self.run_until(&done)?;
+         let monitors = monitors.replace(vec![]);
+         Ok(monitors)
+     }
+
+     fn set_source_mute(
+         &mut self,
+         source_name: &str,
+         mute: bool,
+     ) -> error::Result<()> {
+         let (done, callback) = done_callback();
+         self.context.introspect()
+             .set_source_mute_by_name(source_name, mute, Some(callback));
+         self.run_until(&done)
+     }
+
+     /// `None` if there is no sink by that name.
+     fn sink(&mut self, name: &str) -> error::Result<Option<Sink>> {
+         let found = Rc::new(RefCell::new(None));
+         let done = Rc::new(Cell::new(false));
+         let cb_done = Rc::clone(&done);
+         let cb_found = Rc::clone(&found);
+         self.context.introspect().get_sink_info_by_name(
+             name,
              move |list_result| {
                  match list_result {
                      ListResult::Item(sink) => {
-                         let volume::Volume(avg) = sink.volume.avg();
-                         let volume::Volume(norm) = volume::VOLUME_NORM;
-
-                         let name = if sink.name.is_some() {
-                             sink.name.clone().unwrap().to_string()
-                         } else {
-                             "".to_owned()
-                         };
-                         cb_sink_vec.borrow_mut().push(Sink {
-                             // TODO no idea what this does
-                             volume: ((
-                                 avg * (100 as u32) +
-                                 norm / 2
-                             ) / norm) as u8,
-                             name,
-                             running: sink.state.is_running(),
-                             open: sink.state.is_opened(),
-                             mute: sink.mute,
-                         })
+                         *cb_found.borrow_mut() = Some(Sink::from(sink));
                      },
                      ListResult::End |
-                     ListResult::Error => {
-                         *(cb_done.borrow_mut()) = true;
+                     ListResult::Error => cb_done.set(true),
+                 }
+             }
+         );
+         self.run_until(&done)?;
+         let sink = found.borrow_mut().take();
+         Ok(sink)
+     }
+
+     /// Names of all sinks, in the order the server lists them.
+     fn sink_names(&mut self) -> error::Result<Vec<String>> {
+         let names = Rc::new(RefCell::new(vec![]));
+         let done = Rc::new(Cell::new(false));
+         let cb_done = Rc::clone(&done);
+         let cb_names = Rc::clone(&names);
+         self.context.introspect().get_sink_info_list(
+             move |list_result| {
+                 match list_result {
+                     ListResult::Item(sink) => {
+                         if let Some(name) = &sink.name {
+                             cb_names.borrow_mut().push(name.to_string());
+                         }
                      },
+                     ListResult::End |
+                     ListResult::Error => cb_done.set(true),
                  }
              }
          );
-         loop {
-             let res = self.iterate();
-             if res.is_err() {
-                 return Err(())
+         self.run_until(&done)?;
+         let names = names.replace(vec![]);
+         Ok(names)
+     }
+
+     /// Sets every channel of the sink to the same volume.
+     fn set_volume(&mut self, sink: &Sink, percent: u32) -> error::Result<()> {
+         let volumes = channel_volumes(sink.channels, percent);
+         let (done, callback) = done_callback();
+         self.context.introspect()
+             .set_sink_volume_by_name(&sink.name, &volumes, Some(callback));
+         self.run_until(&done)
+     }
+
+     /// Every sink input, in the order the server lists them.
+     fn streams(&mut self) -> error::Result<Vec<Stream>> {
+         let streams = Rc::new(RefCell::new(vec![]));
+         let done = Rc::new(Cell::new(false));
+         let cb_done = Rc::clone(&done);
+         let cb_streams = Rc::clone(&streams);
+         self.context.introspect().get_sink_input_info_list(
+             move |list_result| {
+                 match list_result {
+                     ListResult::Item(input) => {
+                         cb_streams.borrow_mut().push(Stream::from(input));
+                     },
+                     ListResult::End |
+                     ListResult::Error => cb_done.set(true),
+                 }
              }
+         );
+         self.run_until(&done)?;
+         let streams = streams.replace(vec![]);
+         Ok(streams)
+     }
+
+     fn set_stream_volume(
+         &mut self,
+         stream: &Stream,
+         percent: u32,
+     ) -> error::Result<()> {
+         let volumes = channel_volumes(stream.channels, percent);
+         let (done, callback) = done_callback();
+         self.context.introspect()
+             .set_sink_input_volume(stream.index, &volumes, Some(callback));
+         self.run_until(&done)
+     }
 
-             if *(done.borrow()) {
-                 return Ok(sink_vec.borrow().clone());
+     fn set_mute(&mut self, sink_name: &str, mute: bool) -> error::Result<()> {
+         let (done, callback) = done_callback();
+         self.context.introspect()
+             .set_sink_mute_by_name(sink_name, mute, Some(callback));
+         self.run_until(&done)
+     }
+
+     fn set_default_sink(&mut self, sink_name: &str) -> error::Result<()> {
+         let (done, callback) = done_callback();
+         self.context.set_default_sink(sink_name, callback);
+         self.run_until(&done)
+     }
+
+     /// Moves every playing stream to the given sink.
+     fn move_sink_inputs(&mut self, sink_name: &str) -> error::Result<()> {
+         let inputs = Rc::new(RefCell::new(vec![]));
+         let done = Rc::new(Cell::new(false));
+         let cb_done = Rc::clone(&done);
+         let cb_inputs = Rc::clone(&inputs);
+         self.context.introspect().get_sink_input_info_list(
+             move |list_result| {
+                 match list_result {
+                     ListResult::Item(input) => {
+                         cb_inputs.borrow_mut().push(input.index);
+                     },
+                     ListResult::End |
+                     ListResult::Error => cb_done.set(true),
+                 }
              }
+         );
+         self.run_until(&done)?;
+
+         let inputs = inputs.replace(vec![]);
+         for index in inputs {
+             let (done, callback) = done_callback();
+             self.context.introspect()
+                 .move_sink_input_by_name(index, sink_name, Some(callback));
+             self.run_until(&done)?;
          }
+         Ok(())
+     }
+
+     // Iterates the main loop until a callback raised `done`.
+     fn run_until(&mut self, done: &Rc<Cell<bool>>) -> error::Result<()> {
+         while !done.get() {
+             self.wait()?;
+         }
+         Ok(())
+     }
+
+     /// Blocks until the server sent something.
+     /// Fails once the connection is gone.
+     fn wait(&mut self) -> error::Result<()> {
+        match self.mainloop.iterate(true) {
+            IterateResult::Quit(_) |
+            IterateResult::Err(_) => return Err(pulse_error("the mainloop quit")),
+            IterateResult::Success(_) => {},
+        }
+        match self.context.get_state() {
+            pulse::context::State::Terminated |
+            pulse::context::State::Failed => Err(pulse_error("lost the connection")),
+            _ => Ok(()),
+        }
      }
 
-     fn listen(&mut self) {
-        self.context.subscribe(subscription_masks::ALL, |_| {});
-        self.context.set_subscribe_callback(Some(Box::new(|a,b,c| {
-            println!("{:?} {:?} {:?}", a, b, c);
+     /// The returned flag is raised whenever a sink, a source, a stream or
+     /// the server changed.
+     fn subscribe(&mut self) -> Rc<Cell<bool>> {
+        let changed = Rc::new(Cell::new(false));
+        let cb_changed = Rc::clone(&changed);
+        self.context.set_subscribe_callback(Some(Box::new(move |_, _, _| {
+            cb_changed.set(true);
         })));
-        self.mainloop.run().unwrap();
+        self.context.subscribe(
+            subscription_masks::SINK
+                | subscription_masks::SINK_INPUT
+                | subscription_masks::SOURCE
+                | subscription_masks::SOURCE_OUTPUT
+                | subscription_masks::SERVER,
+            |_| {},
+        );
+        changed
+     }
+
+     /// Raises `flag` whenever something is written to the other end of
+     /// `receiver`, that way other threads can interrupt `wait`.
+     fn wake_on(
+         &mut self,
+         receiver: UnixStream,
+         flag: Rc<Cell<bool>>,
+     ) -> error::Result<()> {
+         if let Err(err) = receiver.set_nonblocking(true) {
+             return Err(Error::Audio(err.to_string()));
+         }
+         let fd = receiver.as_raw_fd();
+         let event = self.mainloop.new_io_event(
+             fd,
+             io_flags::INPUT,
+             Box::new(move |_, _, _| {
+                 let mut buf = [0; 64];
+                 while let Ok(read) = (&receiver).read(&mut buf) {
+                     if read == 0 { break }
+                 }
+                 flag.set(true);
+             }),
+         );
+         match event {
+             Some(event) => {
+                 self.wakeup = Some(event);
+                 Ok(())
+             },
+             None => Err(pulse_error("failed to watch the wakeup socket")),
+         }
      }
 }
 
+fn pulse_error(reason: &str) -> Error {
+    Error::Audio(format!("pulse: {}", reason))
+}
+
+type Callback = Box<dyn FnMut(bool)>;
+
+// The flag is raised once the server answered the operation.
+fn done_callback() -> (Rc<Cell<bool>>, Callback) {
+    let done = Rc::new(Cell::new(false));
+    let cb_done = Rc::clone(&done);
+    (done, Box::new(move |_| cb_done.set(true)))
+}
+
 #[derive(Clone,Debug)]
 struct Sink {
-    volume: u8,
     name: String,
-    running: bool,
-    open: bool,
+    volume: u32,
+    channels: u8,
+    description: Option<String>,
+    mute: bool,
+}
+
+impl<'a> From<&'a SinkInfo<'a>> for Sink {
+    fn from(sink: &'a SinkInfo<'a>) -> Self {
+        Sink {
+            name: sink.name
+                .as_ref()
+                .map(|name| name.to_string())
+                .unwrap_or_default(),
+            volume: percent_of(sink.volume.avg()),
+            channels: sink.volume.channels,
+            description: sink.description
+                .as_ref()
+                .map(|description| description.to_string()),
+            mute: sink.mute,
+        }
+    }
+}
+
+#[derive(Clone,Debug)]
+struct Source {
+    name: String,
+    volume: u32,
+    description: Option<String>,
     mute: bool,
 }
+
+impl<'a> From<&'a SourceInfo<'a>> for Source {
+    fn from(source: &'a SourceInfo<'a>) -> Self {
+        Source {
+            name: source.name
+                .as_ref()
+                .map(|name| name.to_string())
+                .unwrap_or_default(),
+            volume: percent_of(source.volume.avg()),
+            description: source.description
+                .as_ref()
+                .map(|description| description.to_string()),
+            mute: source.mute,
+        }
+    }
+}
+
+impl<'a> From<&'a SinkInputInfo<'a>> for Stream {
+    fn from(input: &'a SinkInputInfo<'a>) -> Self {
+        let application = input.proplist
+            .gets(pulse::proplist::properties::APPLICATION_NAME)
+            .or_else(|| input.name.as_ref().map(|name| name.to_string()))
+            .unwrap_or_default();
+        Stream {
+            index: input.index,
+            application,
+            percent: percent_of(input.volume.avg()),
+            mute: input.mute,
+            corked: input.corked,
+            channels: input.volume.channels,
+        }
+    }
+}
+
+// The same volume on every channel. `ChannelVolumes::set` is broken in this
+// version of the binding, so the values are filled in by hand.
+fn channel_volumes(channels: u8, percent: u32) -> volume::ChannelVolumes {
+    let volume::Volume(norm) = volume::VOLUME_NORM;
+    let mut volumes = volume::ChannelVolumes {
+        channels,
+        ..Default::default()
+    };
+    for value in volumes.values.iter_mut().take(channels as usize) {
+        let raw = u64::from(norm) * u64::from(percent) / 100;
+        *value = volume::Volume(raw.min(u64::from(volume::VOLUME_MAX.0)) as u32);
+    }
+    volumes
+}
+
+// pulse volumes are linear factors, `VOLUME_NORM` being 100%
+fn percent_of(volume: volume::Volume) -> u32 {
+    let volume::Volume(avg) = volume;
+    let volume::Volume(norm) = volume::VOLUME_NORM;
+    let (avg, norm) = (u64::from(avg), u64::from(norm));
+    // rounded, not truncated
+    ((avg * 100 + norm / 2) / norm) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker() -> (Worker, mpsc::Sender<Command>) {
+        let (events, _) = mpsc::channel();
+        let (states, _) = mpsc::channel();
+        let (commands, command_receiver) = mpsc::channel();
+        let (_, wakeup) = UnixStream::pair().unwrap();
+        let worker = Worker {
+            pause: pause::Pause::unsignaled(),
+            events,
+            states,
+            commands: command_receiver,
+            wakeup,
+            last_state: None,
+            pinned: None,
+        };
+        (worker, commands)
+    }
+
+    #[test]
+    fn commands_keep_pins_apart() {
+        let (mut worker, commands) = worker();
+        commands.send(Command::Pin(Some("hdmi".to_owned()))).unwrap();
+        commands.send(Command::ToggleMute).unwrap();
+
+        // without a server the backoff loop drops what it can't carry out,
+        // but not the pin
+        assert!(matches!(worker.commands()[..], [Command::ToggleMute]));
+        assert_eq!(worker.pinned.as_deref(), Some("hdmi"));
+
+        // later commands leave the pin alone
+        commands.send(Command::SetVolume(50)).unwrap();
+        let actions = worker.commands();
+        assert!(matches!(actions[..], [Command::SetVolume(50)]));
+        assert_eq!(worker.pinned.as_deref(), Some("hdmi"));
+
+        commands.send(Command::Pin(None)).unwrap();
+        worker.commands();
+        assert_eq!(worker.pinned, None);
+    }
+}
@@ -0,0 +1,459 @@
+use std::{fs,io,time};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::{HashMap,VecDeque};
+use std::path::{Path,PathBuf};
+use crate::{format,notify};
+use crate::format::Value;
+use crate::error::{self,Error};
+use crate::block::{Block,Item};
+use crate::sysfs::{read_attribute,read_number};
+
+const POWER_SUPPLY: &str = "/sys/class/power_supply";
+// number of updates the charge rate is averaged over
+const RATE_WINDOW: usize = 10;
+
+/// Whether the batteries are shown as one or each on its own.
+#[derive(Clone,Copy,Debug,PartialEq,serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// One capacity for all batteries, weighted by how much they hold.
+    Total,
+    PerBattery,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Kind {
+    Battery,
+    Mains,
+    Usb,
+    Other,
+}
+
+// An entry of `/sys/class/power_supply`.
+struct Supply {
+    name: String,
+    path: PathBuf,
+    kind: Kind,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Status {
+    Charging,
+    Discharging,
+    Full,
+    NotCharging,
+    Unknown,
+}
+
+#[derive(Debug)]
+struct Battery {
+    name: String,
+    /// In percent.
+    capacity: f64,
+    /// Whether the amounts below are energy, µWh and µW. Otherwise they are
+    /// charge, µAh and µA, because the driver reports nothing else.
+    energy: bool,
+    /// What the battery holds when full.
+    full: Option<f64>,
+    /// What the battery held when it was new.
+    design: Option<f64>,
+    cycles: Option<u64>,
+    /// What the battery holds right now.
+    now: Option<f64>,
+    /// How fast the battery charges or discharges right now.
+    rate: Option<f64>,
+    status: Status,
+}
+
+// Recent rates of a battery, they jump around a lot.
+struct Window {
+    status: Status,
+    rates: VecDeque<f64>,
+}
+
+impl Window {
+    fn new(status: Status) -> Self {
+        Window { status, rates: VecDeque::with_capacity(RATE_WINDOW) }
+    }
+
+    // The average including `rate`. Starting or stopping to charge makes
+    // older rates meaningless.
+    fn push(&mut self, status: Status, rate: f64) -> f64 {
+        if status != self.status {
+            *self = Window::new(status);
+        }
+        if self.rates.len() == RATE_WINDOW {
+            self.rates.pop_front();
+        }
+        self.rates.push_back(rate);
+        self.rates.iter().sum::<f64>() / self.rates.len() as f64
+    }
+}
+
+/// Capacities in percent the block reacts to: red below `low`, green above
+/// `high`, urgent below `critical` and a notification at each of `notify`.
+pub struct Thresholds {
+    pub low: u64,
+    pub high: u64,
+    pub critical: u64,
+    pub notify: Vec<u64>,
+}
+
+/// Finds every battery and power adapter in `/sys/class/power_supply`. The
+/// block is empty, and so hidden, on machines without a battery. `{time}` is
+/// the time until the battery is empty or full, `{watts}` the power going in
+/// or out, `{health}` how much of its design capacity is left and `{cycles}`
+/// how often it was charged. While discharging, a notification is sent for
+/// each of the `notify` thresholds reached.
+pub struct BatteryBlock {
+    interval: time::Duration,
+    format: String,
+    mode: Mode,
+    // names of the batteries to show, all of them if empty
+    batteries: Vec<String>,
+    thresholds: Thresholds,
+    // thresholds notified about since the battery last charged, shared so a
+    // config reload doesn't notify again
+    notified: Rc<RefCell<Vec<u64>>>,
+    windows: HashMap<String, Window>,
+}
+
+impl BatteryBlock {
+    pub fn new(
+        interval: Option<time::Duration>,
+        format: Option<String>,
+        mode: Mode,
+        batteries: Vec<String>,
+        thresholds: Thresholds,
+        notified: Rc<RefCell<Vec<u64>>>,
+    ) -> Self {
+        BatteryBlock {
+            interval: interval.unwrap_or(time::Duration::from_secs(30)),
+            format: format.unwrap_or_else(|| {
+                "bat {capacity}{status}[ {time}]".to_owned()
+            }),
+            mode,
+            batteries,
+            thresholds,
+            notified,
+            windows: HashMap::new(),
+        }
+    }
+
+    fn read(&mut self) -> error::Result<Item> {
+        let supplies = discover()?;
+        let mut batteries = vec![];
+        // one broken battery doesn't hide the others
+        let mut failed = None;
+        let mut online = false;
+        for supply in &supplies {
+            match supply.kind {
+                Kind::Battery => {
+                    let wanted = self.batteries.is_empty()
+                        || self.batteries.contains(&supply.name);
+                    if wanted && is_present(supply) {
+                        let mut battery = match read_battery(supply) {
+                            Ok(battery) => battery,
+                            Err(err) => {
+                                failed = failed.or(Some(err));
+                                continue;
+                            },
+                        };
+                        battery.rate = battery.rate.map(|rate| {
+                            self.windows
+                                .entry(battery.name.clone())
+                                .or_insert_with(|| Window::new(battery.status))
+                                .push(battery.status, rate)
+                        });
+                        batteries.push(battery);
+                    }
+                },
+                Kind::Mains |
+                Kind::Usb => online = online || read_online(supply),
+                Kind::Other => {},
+            }
+        }
+
+        if batteries.is_empty() {
+            if let Some(err) = failed {
+                return Err(err);
+            }
+            return Ok(Item {
+                name: self.name().to_owned(),
+                ..Default::default()
+            });
+        }
+
+        let is_charging = online || batteries
+            .iter()
+            .any(|battery| battery.status == Status::Charging);
+        let capacity = total_capacity(&batteries);
+        let text = match self.mode {
+            Mode::Total => {
+                let total = combine(&batteries, capacity, is_charging);
+                self.render("bat", &total)
+            },
+            Mode::PerBattery => batteries
+                .iter()
+                .map(|battery| self.render(&battery.name, battery))
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+
+        let capacity = capacity.round() as u64;
+        self.notify(capacity, is_charging);
+        let good = if is_charging {
+            Some(true)
+        } else if capacity < self.thresholds.low {
+            Some(false)
+        } else if capacity > self.thresholds.high {
+            Some(true)
+        } else {
+            None
+        };
+
+        Ok(Item {
+            name: self.name().to_owned(),
+            text,
+            good,
+            urgent: Some(!is_charging && capacity < self.thresholds.critical),
+            ..Default::default()
+        })
+    }
+
+    // Each threshold is notified about once per discharge, only the lowest
+    // one if several were reached at once.
+    fn notify(&mut self, capacity: u64, is_charging: bool) {
+        let mut notified = self.notified.borrow_mut();
+        if is_charging {
+            notified.clear();
+            return;
+        }
+        let reached: Vec<u64> = self.thresholds.notify
+            .iter()
+            .cloned()
+            .filter(|threshold| {
+                capacity <= *threshold && !notified.contains(threshold)
+            })
+            .collect();
+        let lowest = match reached.iter().min() {
+            Some(lowest) => *lowest,
+            None => return,
+        };
+        notified.extend(reached);
+        drop(notified);
+
+        let urgency = if Some(&lowest) == self.thresholds.notify.iter().min() {
+            notify::Urgency::Critical
+        } else {
+            notify::Urgency::Normal
+        };
+        let body = format!("{}% left", capacity);
+        if let Err(err) = notify::send("Battery low", &body, urgency) {
+            error::log(&err.in_block(self.name()));
+        }
+    }
+
+    fn render(&self, name: &str, battery: &Battery) -> String {
+        let affix = if battery.status == Status::Charging { "\u{1f5f2}" } else { "%" };
+        let time = remaining(battery).map(|hours| {
+            let minutes = (hours * 60.0).round() as u64;
+            format!("{}:{:02}", minutes / 60, minutes % 60)
+        });
+        let watts = battery.rate
+            .filter(|_| battery.energy)
+            .map(|rate| Value::number(rate / 1_000_000.0, 1));
+        let health = match (battery.full, battery.design) {
+            (Some(full), Some(design)) if design > 0.0 => {
+                Value::whole(full / design * 100.0)
+            },
+            _ => Value::Missing,
+        };
+        let cycles = battery.cycles.map(|cycles| Value::whole(cycles as f64));
+        format::render(&self.format, &[
+            ("name", name.to_lowercase().into()),
+            ("capacity", Value::whole(battery.capacity)),
+            ("status", affix.into()),
+            ("time", time.into()),
+            ("watts", watts.into()),
+            ("health", health),
+            ("cycles", cycles.into()),
+        ])
+    }
+}
+
+impl Block for BatteryBlock {
+    fn name(&self) -> &str { "Battery" }
+
+    fn interval(&self) -> time::Duration { self.interval }
+
+    fn update(&mut self) -> error::Result<Item> {
+        self.read()
+    }
+}
+
+// Batteries come and go, e.g. the second one of a ThinkPad, so this is done
+// on every update.
+fn discover() -> error::Result<Vec<Supply>> {
+    let entries = match fs::read_dir(POWER_SUPPLY) {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(vec![]);
+        },
+        Err(err) => return Err(Error::read(POWER_SUPPLY, err)),
+    };
+
+    let mut supplies = vec![];
+    for entry in entries {
+        let entry = entry.map_err(|err| Error::read(POWER_SUPPLY, err))?;
+        let path = entry.path();
+        // a supply that can't say what it is gets left out
+        let kind = match optional(read_attribute(&path, "type")).as_deref() {
+            // mice and keyboards report their batteries too
+            Some("Battery") if !is_device_scoped(&path) => Kind::Battery,
+            Some("Mains") => Kind::Mains,
+            Some(kind) if kind.starts_with("USB") => Kind::Usb,
+            _ => Kind::Other,
+        };
+        supplies.push(Supply {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            path,
+            kind,
+        });
+    }
+    // BAT0 before BAT1
+    supplies.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(supplies)
+}
+
+// Only the capacity has to be there, everything else that can't be read is
+// unknown. Drivers fail reads of values they don't know right now.
+fn read_battery(supply: &Supply) -> error::Result<Battery> {
+    let capacity = read_number(&supply.path.join("capacity"))?
+        .ok_or_else(|| Error::parse(&supply.path, "no capacity"))?;
+    let number = |attribute| optional(read_number(&supply.path.join(attribute)));
+    let energy = number("energy_now").is_some();
+    let (full, design, now, rate) = if energy {
+        (
+            number("energy_full"),
+            number("energy_full_design"),
+            number("energy_now"),
+            number("power_now"),
+        )
+    } else {
+        (
+            number("charge_full"),
+            number("charge_full_design"),
+            number("charge_now"),
+            number("current_now"),
+        )
+    };
+    // some drivers report 0 when they don't know
+    let cycles = number("cycle_count")
+        .filter(|cycles| *cycles > 0.0)
+        .map(|cycles| cycles as u64);
+    let status = match optional(read_attribute(&supply.path, "status")).as_deref() {
+        Some("Charging") => Status::Charging,
+        Some("Discharging") => Status::Discharging,
+        Some("Full") => Status::Full,
+        Some("Not charging") => Status::NotCharging,
+        _ => Status::Unknown,
+    };
+    Ok(Battery {
+        name: supply.name.clone(),
+        capacity,
+        energy,
+        full,
+        design,
+        cycles,
+        now,
+        // some drivers report discharging as negative
+        rate: rate.map(f64::abs).filter(|rate| *rate > 0.0),
+        status,
+    })
+}
+
+// All batteries as one. Amounts only add up if every battery reports them,
+// in the same unit.
+fn combine(batteries: &[Battery], capacity: f64, is_charging: bool) -> Battery {
+    let energy = batteries.iter().all(|battery| battery.energy);
+    let same_unit = same_units(batteries);
+    let sum = |amount: fn(&Battery) -> Option<f64>| -> Option<f64> {
+        if !same_unit {
+            return None;
+        }
+        batteries.iter().map(amount).sum()
+    };
+    Battery {
+        name: "bat".to_owned(),
+        capacity,
+        energy,
+        full: sum(|battery| battery.full),
+        design: sum(|battery| battery.design),
+        // the most worn battery
+        cycles: batteries.iter().filter_map(|battery| battery.cycles).max(),
+        now: sum(|battery| battery.now),
+        // idle batteries don't count
+        rate: Some(batteries.iter().filter_map(|battery| battery.rate).sum())
+            .filter(|rate: &f64| same_unit && *rate > 0.0),
+        status: if is_charging { Status::Charging } else {
+            batteries
+                .iter()
+                .map(|battery| battery.status)
+                .find(|status| *status == Status::Discharging)
+                .unwrap_or(Status::Unknown)
+        },
+    }
+}
+
+// Hours until the battery is empty or full, if it is on its way there.
+fn remaining(battery: &Battery) -> Option<f64> {
+    let rate = battery.rate?;
+    match battery.status {
+        Status::Discharging => Some(battery.now? / rate),
+        Status::Charging => Some((battery.full? - battery.now?).max(0.0) / rate),
+        _ => None,
+    }
+}
+
+fn is_device_scoped(supply: &Path) -> bool {
+    optional(read_attribute(supply, "scope")).as_deref() == Some("Device")
+}
+
+// Empty bays of hot swappable batteries are still listed, reading them fails.
+fn is_present(supply: &Supply) -> bool {
+    optional(read_attribute(&supply.path, "present")).as_deref() != Some("0")
+}
+
+fn read_online(supply: &Supply) -> bool {
+    optional(read_attribute(&supply.path, "online")).as_deref() == Some("1")
+}
+
+// Energy and charge don't add up, batteries can report either.
+fn same_units(batteries: &[Battery]) -> bool {
+    let energy = batteries.iter().filter(|battery| battery.energy).count();
+    energy == 0 || energy == batteries.len()
+}
+
+// Batteries holding more count more. If any of them doesn't say how much it
+// holds, or they say it in different units, they all count the same.
+fn total_capacity(batteries: &[Battery]) -> f64 {
+    let weights: Option<Vec<f64>> = batteries
+        .iter()
+        .map(|battery| battery.full.filter(|full| *full > 0.0))
+        .collect();
+    let weights = weights.filter(|_| same_units(batteries));
+    let weights = weights.unwrap_or_else(|| vec![1.0; batteries.len()]);
+    let total: f64 = weights.iter().sum();
+    batteries
+        .iter()
+        .zip(&weights)
+        .map(|(battery, weight)| battery.capacity * weight)
+        .sum::<f64>() / total
+}
+
+// A value that failed to read counts as unknown.
+fn optional<T>(value: error::Result<Option<T>>) -> Option<T> {
+    value.unwrap_or(None)
+}
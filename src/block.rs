@@ -0,0 +1,99 @@
+use std::time;
+use crate::{error,i3};
+
+/// Something that shows up on the bar. Blocks are driven by the scheduler,
+/// which calls `update` whenever `next_update` says so and whenever the block
+/// was clicked. A block that fails is shown as an error until it recovers.
+pub trait Block {
+    /// Identifies the kind of block on the bar and in click events, the
+    /// scheduler tells blocks of the same kind apart by their instance.
+    fn name(&self) -> &str;
+
+    fn interval(&self) -> time::Duration;
+
+    /// When to update next, given the block was just updated at `now`.
+    fn next_update(&self, now: time::Instant) -> time::Instant {
+        now + self.interval()
+    }
+
+    fn update(&mut self) -> error::Result<Item>;
+
+    fn click(&mut self, _event: &i3::ClickEvent) -> error::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Item {
+    pub name: String,
+    pub text: String,
+    pub good: Option<bool>,
+    pub short_text: Option<String>,
+    pub instance: Option<String>,
+    pub background: Option<String>,
+    pub border: Option<String>,
+    pub border_top: Option<u32>,
+    pub border_right: Option<u32>,
+    pub border_bottom: Option<u32>,
+    pub border_left: Option<u32>,
+    pub min_width: Option<i3::MinWidth>,
+    pub align: Option<i3::Align>,
+    pub urgent: Option<bool>,
+    pub separator: Option<bool>,
+    pub separator_block_width: Option<u32>,
+    pub markup: Option<i3::Markup>,
+}
+
+impl Item {
+    pub fn to_i3_item (self: &Item) -> i3::Item {
+        let color = match self.good {
+            Some(true) => "#00FF00".to_owned(),
+            Some(false) => "#FF0000".to_owned(),
+            None => "#FFFFFF".to_owned(),
+        };
+
+        i3::Item {
+            name: self.name.clone(),
+            full_text: self.text.clone(),
+            color,
+            short_text: self.short_text.clone(),
+            instance: self.instance.clone(),
+            background: self.background.clone(),
+            border: self.border.clone(),
+            border_top: self.border_top,
+            border_right: self.border_right,
+            border_bottom: self.border_bottom,
+            border_left: self.border_left,
+            min_width: self.min_width.clone(),
+            align: self.align,
+            urgent: self.urgent,
+            separator: self.separator,
+            separator_block_width: self.separator_block_width,
+            markup: self.markup,
+        }
+    }
+}
+
+/// Appearance set in the config, it wins over whatever the block chose.
+#[derive(Default)]
+pub struct Style {
+    pub min_width: Option<i3::MinWidth>,
+    pub align: Option<i3::Align>,
+    pub markup: Option<i3::Markup>,
+    /// Leave a failing block off the bar instead of showing an error.
+    pub hide_on_error: bool,
+}
+
+impl Style {
+    pub fn apply(&self, item: &mut Item) {
+        if self.min_width.is_some() {
+            item.min_width = self.min_width.clone();
+        }
+        if self.align.is_some() {
+            item.align = self.align;
+        }
+        if self.markup.is_some() {
+            item.markup = self.markup;
+        }
+    }
+}
@@ -0,0 +1,48 @@
+extern crate chrono;
+
+use std::time;
+use crate::error;
+use crate::block::{Block,Item};
+
+pub struct ClockBlock {
+    interval: time::Duration,
+    format: String,
+}
+
+impl ClockBlock {
+    /// `format` is handed to chrono, see `chrono::format::strftime`. The
+    /// config checks it when it is loaded, chrono panics on broken ones.
+    pub fn new(interval: Option<time::Duration>, format: Option<String>) -> Self {
+        ClockBlock {
+            interval: interval.unwrap_or(time::Duration::from_secs(60)),
+            format: format.unwrap_or_else(|| "%Y.%m.%d %H.%M".to_owned()),
+        }
+    }
+}
+
+impl Block for ClockBlock {
+    fn name(&self) -> &str { "DateTime" }
+
+    fn interval(&self) -> time::Duration { self.interval }
+
+    // Wake up right when the shown time changes, e.g. on the minute, instead
+    // of up to an interval late.
+    fn next_update(&self, now: time::Instant) -> time::Instant {
+        let since_epoch = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let interval = self.interval.as_millis().max(1);
+        let passed = since_epoch.as_millis() % interval;
+        now + time::Duration::from_millis((interval - passed) as u64)
+    }
+
+    fn update(&mut self) -> error::Result<Item> {
+        let now = chrono::Local::now();
+        Ok(Item {
+            name: self.name().to_owned(),
+            text: now.format(&self.format).to_string(),
+            good: None,
+            ..Default::default()
+        })
+    }
+}
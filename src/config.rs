@@ -0,0 +1,418 @@
+extern crate toml;
+extern crate signal_hook;
+
+use std::{env,fs,io,time};
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::thread;
+use std::sync::mpsc;
+use std::time::SystemTime;
+use signal_hook::iterator::Signals;
+use serde::Deserialize;
+use chrono::format::StrftimeItems;
+use crate::{i3,audio,battery,block,pause,scheduler,units};
+use crate::error::{self,Error};
+
+/// Contents of `$XDG_CONFIG_HOME/mybar/config.toml`. Blocks are shown in the
+/// order they are listed in.
+///
+/// ```toml
+/// coalesce = 50
+///
+/// [[block]]
+/// type = "battery"
+/// interval = 30
+/// format = "bat {capacity}{status}"
+/// low = 20
+/// ```
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Milliseconds to wait for more updates before writing a line. Only
+    /// read on startup.
+    #[serde(default)]
+    pub coalesce: u64,
+    /// Only read on startup, except for the sink.
+    #[serde(default)]
+    pub audio: AudioConfig,
+    #[serde(rename = "block", default = "default_blocks")]
+    pub blocks: Vec<BlockConfig>,
+}
+
+/// The `[audio]` table.
+///
+/// ```toml
+/// [audio]
+/// backend = "alsa"
+/// device = "hw:0"
+/// control = "PCM"
+/// ```
+#[derive(Clone,serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AudioConfig {
+    #[serde(default)]
+    pub backend: audio::Backend,
+    /// Name of the sink the volume blocks show, defaults to the server's
+    /// default sink. Every volume block shows the same sink.
+    pub sink: Option<String>,
+    /// ALSA mixer device.
+    #[serde(default = "default_alsa_device")]
+    #[cfg_attr(not(feature = "alsa"), allow(dead_code))]
+    pub device: String,
+    /// ALSA simple mixer control, `Master` or else `PCM` if not set.
+    #[cfg_attr(not(feature = "alsa"), allow(dead_code))]
+    pub control: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(try_from = "BlockTable")]
+pub struct BlockConfig {
+    pub kind: BlockKind,
+    /// Refresh interval in seconds, every block has its own default.
+    pub interval: Option<u64>,
+    pub format: Option<String>,
+    pub min_width: Option<i3::MinWidth>,
+    pub align: Option<i3::Align>,
+    pub markup: Option<i3::Markup>,
+    /// How amounts are shown, for blocks that show any.
+    pub units: units::UnitsConfig,
+    /// Leave the block off the bar while it fails.
+    pub hide_on_error: bool,
+}
+
+// A `[[block]]` as written. serde can't reject unknown keys next to a
+// flattened enum, so the keys every block takes are read first and whatever
+// is left has to fit the block's kind.
+#[derive(serde::Deserialize)]
+struct BlockTable {
+    interval: Option<u64>,
+    format: Option<String>,
+    min_width: Option<i3::MinWidth>,
+    align: Option<i3::Align>,
+    markup: Option<i3::Markup>,
+    #[serde(default)]
+    units: units::UnitsConfig,
+    #[serde(default)]
+    hide_on_error: bool,
+    #[serde(flatten)]
+    kind: toml::value::Table,
+}
+
+impl TryFrom<BlockTable> for BlockConfig {
+    type Error = toml::de::Error;
+
+    fn try_from(table: BlockTable) -> Result<Self, Self::Error> {
+        Ok(BlockConfig {
+            kind: BlockKind::deserialize(toml::Value::Table(table.kind))?,
+            interval: table.interval,
+            format: table.format,
+            min_width: table.min_width,
+            align: table.align,
+            markup: table.markup,
+            units: table.units,
+            hide_on_error: table.hide_on_error,
+        })
+    }
+}
+
+// Kinds without options are empty structs too, unit variants would take any
+// key.
+#[derive(serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum BlockKind {
+    Inet {},
+    Traffic {},
+    Memory {
+        /// Share of used memory, above which the block turns red.
+        #[serde(default = "default_memory_critical")]
+        critical: f64,
+        /// Memory pressure in percent, from which on the block turns red
+        /// instead. Needs a kernel with pressure stall information.
+        pressure: Option<f64>,
+    },
+    Cpu {
+        /// Usage in percent, from which on the block turns red.
+        #[serde(default = "default_cpu_critical")]
+        critical: f64,
+    },
+    /// CPU temperature and frequency.
+    Thermal {
+        /// Label of the temperature sensor, e.g. `Package id 0`, or
+        /// `coretemp/Core 0` to tell chips apart. Defaults to the CPU package.
+        sensor: Option<String>,
+        /// Degrees Celsius the hardware can take, defaults to the sensor's
+        /// critical trip point.
+        critical: Option<f64>,
+        /// Degrees below `critical` from which on the block turns red.
+        #[serde(default = "default_thermal_margin")]
+        margin: f64,
+    },
+    Volume {
+        /// Percent to change the volume by when scrolling.
+        #[serde(default = "default_volume_step")]
+        step: u32,
+        /// Scrolling never raises the volume beyond this many percent, nor
+        /// beyond what pulse considers the most a UI should offer.
+        #[serde(default = "default_volume_max")]
+        max: u32,
+    },
+    Microphone {},
+    /// Application streams.
+    Streams {
+        #[serde(default = "default_volume_step")]
+        step: u32,
+        #[serde(default = "default_volume_max")]
+        max: u32,
+    },
+    Battery {
+        #[serde(default = "default_battery_mode")]
+        mode: battery::Mode,
+        /// Names in `/sys/class/power_supply`, e.g. `BAT0`, all batteries are
+        /// shown if empty.
+        #[serde(default)]
+        batteries: Vec<String>,
+        #[serde(default = "default_battery_low")]
+        low: u64,
+        #[serde(default = "default_battery_high")]
+        high: u64,
+        /// Capacity in percent below which the block is urgent while
+        /// discharging.
+        #[serde(default = "default_battery_critical")]
+        critical: u64,
+        /// Capacities in percent to send a notification at while
+        /// discharging, needs the `dbus` feature and is empty without it.
+        #[serde(default = "default_battery_notify")]
+        notify: Vec<u64>,
+    },
+    DateTime {},
+}
+
+impl BlockKind {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            BlockKind::Memory { critical, pressure } => {
+                require(
+                    *critical > 0.0 && *critical <= 1.0,
+                    "`critical` must be a share above 0 and up to 1",
+                )?;
+                require(
+                    pressure.is_none_or(|pressure| pressure > 0.0),
+                    "`pressure` must be above 0",
+                )
+            },
+            BlockKind::Cpu { critical } => require(
+                *critical > 0.0 && *critical <= 100.0,
+                "`critical` must be above 0 and up to 100",
+            ),
+            BlockKind::Thermal { critical, margin, .. } => {
+                require(
+                    critical.is_none_or(|critical| critical > 0.0),
+                    "`critical` must be above 0",
+                )?;
+                require(*margin >= 0.0, "`margin` can't be negative")
+            },
+            BlockKind::Volume { step, max } |
+            BlockKind::Streams { step, max } => {
+                require(*step > 0, "`step` must be above 0")?;
+                require(*max > 0, "`max` must be above 0")
+            },
+            BlockKind::Battery { low, high, critical, notify, .. } => {
+                require(low <= high, "`low` can't be above `high`")?;
+                require(
+                    *high <= 100
+                        && *critical <= 100
+                        && notify.iter().all(|capacity| *capacity <= 100),
+                    "capacities can't be above 100",
+                )
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+fn require(ok: bool, reason: &str) -> Result<(), String> {
+    if ok { Ok(()) } else { Err(reason.to_owned()) }
+}
+
+impl Config {
+    /// Reads the config file, falls back to the built-in bar if there is none.
+    pub fn load() -> error::Result<Self> {
+        let path = match config_path() {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+        let raw = match fs::read_to_string(&path) {
+            Ok(raw) => raw,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(Config::default());
+            },
+            Err(err) => return Err(Error::read(path, err)),
+        };
+        let config: Config = toml::from_str(&raw).map_err(|err| Error::Config {
+            path: path.clone(),
+            reason: err.to_string(),
+        })?;
+        config.validate()
+            .map_err(|reason| Error::Config { path, reason })?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (index, block) in self.blocks.iter().enumerate() {
+            block.validate()
+                .map_err(|reason| format!("block {}: {}", index + 1, reason))?;
+        }
+        Ok(())
+    }
+}
+
+/// Asks the bar to read its config again on SIGHUP and whenever the file's
+/// modification time changes.
+pub fn watch(
+    events: mpsc::Sender<scheduler::Event>,
+    pause: pause::Pause,
+) -> error::Result<()> {
+    let signals = Signals::new([signal_hook::SIGHUP])
+        .map_err(Error::Signal)?;
+
+    let hangup_events = events.clone();
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            if hangup_events.send(scheduler::Event::Reload).is_err() {
+                break;
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        let mut last_modified = modified();
+        loop {
+            pause.wait();
+            thread::sleep(time::Duration::from_secs(2));
+            let modified = modified();
+            if modified != last_modified {
+                last_modified = modified;
+                if events.send(scheduler::Event::Reload).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn modified() -> Option<SystemTime> {
+    fs::metadata(config_path()?).ok()?.modified().ok()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            coalesce: 0,
+            audio: AudioConfig::default(),
+            blocks: default_blocks(),
+        }
+    }
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            backend: audio::Backend::default(),
+            sink: None,
+            device: default_alsa_device(),
+            control: None,
+        }
+    }
+}
+
+impl BlockConfig {
+    fn new(kind: BlockKind) -> Self {
+        BlockConfig {
+            kind,
+            interval: None,
+            format: None,
+            min_width: None,
+            align: None,
+            markup: None,
+            units: units::UnitsConfig::default(),
+            hide_on_error: false,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let (BlockKind::DateTime {}, Some(format)) = (&self.kind, &self.format) {
+            let broken = StrftimeItems::new(format)
+                .any(|item| item == chrono::format::Item::Error);
+            if broken {
+                return Err(format!("invalid date format `{}`", format));
+            }
+        }
+        self.kind.validate()
+    }
+
+    pub fn interval(&self) -> Option<time::Duration> {
+        self.interval.map(|secs| time::Duration::from_secs(secs.max(1)))
+    }
+
+    pub fn style(&self) -> block::Style {
+        block::Style {
+            min_width: self.min_width.clone(),
+            align: self.align,
+            markup: self.markup,
+            hide_on_error: self.hide_on_error,
+        }
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    let config_home = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("mybar").join("config.toml"))
+}
+
+fn default_blocks() -> Vec<BlockConfig> {
+    vec![
+        BlockConfig::new(BlockKind::Inet {}),
+        BlockConfig::new(BlockKind::Traffic {}),
+        BlockConfig::new(BlockKind::Memory {
+            critical: default_memory_critical(),
+            pressure: None,
+        }),
+        BlockConfig::new(BlockKind::Cpu {
+            critical: default_cpu_critical(),
+        }),
+        BlockConfig::new(BlockKind::Volume {
+            step: default_volume_step(),
+            max: default_volume_max(),
+        }),
+        BlockConfig::new(BlockKind::Microphone {}),
+        BlockConfig::new(BlockKind::Battery {
+            mode: default_battery_mode(),
+            batteries: vec![],
+            low: default_battery_low(),
+            high: default_battery_high(),
+            critical: default_battery_critical(),
+            notify: default_battery_notify(),
+        }),
+        BlockConfig::new(BlockKind::DateTime {}),
+    ]
+}
+
+fn default_alsa_device() -> String { "default".to_owned() }
+fn default_memory_critical() -> f64 { 0.8 }
+fn default_cpu_critical() -> f64 { 90.0 }
+fn default_thermal_margin() -> f64 { 10.0 }
+fn default_volume_step() -> u32 { 5 }
+fn default_volume_max() -> u32 { 150 }
+fn default_battery_mode() -> battery::Mode { battery::Mode::Total }
+fn default_battery_low() -> u64 { 30 }
+fn default_battery_high() -> u64 { 70 }
+fn default_battery_critical() -> u64 { 10 }
+// nothing to notify with otherwise, every try would only log a failure
+fn default_battery_notify() -> Vec<u64> {
+    if cfg!(feature = "dbus") { vec![15, 5] } else { vec![] }
+}
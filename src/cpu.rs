@@ -0,0 +1,168 @@
+use std::{fs,time};
+use std::str::FromStr;
+use crate::format::{self,Value};
+use crate::error::{self,Error};
+use crate::block::{Block,Item};
+
+const STAT: &str = "/proc/stat";
+const LOADAVG: &str = "/proc/loadavg";
+
+// Jiffies a CPU spent on each kind of work since boot, from a `cpu` line of
+// `/proc/stat`. Guest time is already part of user time.
+#[derive(Clone,Copy,Debug,Default)]
+struct Times {
+    user: u64,
+    nice: u64,
+    system: u64,
+    idle: u64,
+    iowait: u64,
+    irq: u64,
+    softirq: u64,
+    steal: u64,
+}
+
+impl Times {
+    fn total(&self) -> u64 {
+        self.user + self.nice + self.system + self.idle + self.iowait
+            + self.irq + self.softirq + self.steal
+    }
+
+    // what was spent from `before` until now
+    fn since(&self, before: &Times) -> Times {
+        Times {
+            user: self.user.saturating_sub(before.user),
+            nice: self.nice.saturating_sub(before.nice),
+            system: self.system.saturating_sub(before.system),
+            idle: self.idle.saturating_sub(before.idle),
+            iowait: self.iowait.saturating_sub(before.iowait),
+            irq: self.irq.saturating_sub(before.irq),
+            softirq: self.softirq.saturating_sub(before.softirq),
+            steal: self.steal.saturating_sub(before.steal),
+        }
+    }
+
+    /// Share of the time spent busy, in percent. Waiting for IO is idle.
+    fn usage(&self) -> f64 {
+        self.share(self.total() - self.idle - self.iowait)
+    }
+
+    fn share(&self, jiffies: u64) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            total => jiffies as f64 / total as f64 * 100.0,
+        }
+    }
+}
+
+/// CPU utilization between two updates from `/proc/stat`: `{usage}` in
+/// total, `{cores}` for every core, `{iowait}` and `{steal}`, all in percent.
+/// `{load1}`, `{load5}` and `{load15}` are the load averages, `{load}` is
+/// the 5 minute one. The block turns red once usage reaches `critical`.
+pub struct CpuBlock {
+    interval: time::Duration,
+    format: String,
+    critical: f64,
+    // the total first, then every core
+    last: Vec<Times>,
+}
+
+impl CpuBlock {
+    pub fn new(
+        interval: Option<time::Duration>,
+        format: Option<String>,
+        critical: f64,
+    ) -> Self {
+        CpuBlock {
+            interval: interval.unwrap_or(time::Duration::from_secs(1)),
+            format: format.unwrap_or_else(|| "cpu {usage}%".to_owned()),
+            critical,
+            last: vec![],
+        }
+    }
+}
+
+impl Block for CpuBlock {
+    fn name(&self) -> &str { "CPU" }
+
+    fn interval(&self) -> time::Duration { self.interval }
+
+    fn update(&mut self) -> error::Result<Item> {
+        let times = read_stat()?;
+        // the first update shows the usage since boot
+        let spent: Vec<Times> = times
+            .iter()
+            .enumerate()
+            .map(|(cpu, now)| match self.last.get(cpu) {
+                Some(before) => now.since(before),
+                None => *now,
+            })
+            .collect();
+        self.last = times;
+
+        let total = spent[0];
+        let usage = total.usage();
+        let cores = spent[1..]
+            .iter()
+            .map(|core| format!("{:.0}", core.usage()))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let loads = read_loadavg()?;
+
+        Ok(Item {
+            name: self.name().to_owned(),
+            text: format::render(&self.format, &[
+                ("usage", Value::whole(usage)),
+                ("cores", cores.into()),
+                ("iowait", Value::whole(total.share(total.iowait))),
+                ("steal", Value::whole(total.share(total.steal))),
+                ("load", loads[1].as_str().into()),
+                ("load1", loads[0].as_str().into()),
+                ("load5", loads[1].as_str().into()),
+                ("load15", loads[2].as_str().into()),
+            ]),
+            good: if usage >= self.critical { Some(false) } else { None },
+            ..Default::default()
+        })
+    }
+}
+
+// The summary line comes first, the cores follow in order.
+fn read_stat() -> error::Result<Vec<Times>> {
+    let stat = fs::read_to_string(STAT).map_err(|err| Error::read(STAT, err))?;
+    let times = stat
+        .lines()
+        .filter(|line| line.starts_with("cpu"))
+        .map(parse_times)
+        .collect::<error::Result<Vec<_>>>()?;
+    if times.is_empty() {
+        return Err(Error::parse(STAT, "no cpu lines"));
+    }
+    Ok(times)
+}
+
+// `cpu0 21341 0 3421 228813 255 0 8 2378 0 0`, older kernels leave out the
+// last columns.
+fn parse_times(line: &str) -> error::Result<Times> {
+    let mut columns = [0; 8];
+    let values = line.split_whitespace().skip(1).take(columns.len());
+    for (column, value) in columns.iter_mut().zip(values) {
+        *column = u64::from_str(value).map_err(|err| Error::parse(STAT, err))?;
+    }
+    let [user, nice, system, idle, iowait, irq, softirq, steal] = columns;
+    Ok(Times { user, nice, system, idle, iowait, irq, softirq, steal })
+}
+
+// The 1, 5 and 15 minute averages, as the kernel formats them.
+fn read_loadavg() -> error::Result<Vec<String>> {
+    let loadavg = fs::read_to_string(LOADAVG)
+        .map_err(|err| Error::read(LOADAVG, err))?;
+    let loads: Vec<String> = loadavg
+        .split(' ')
+        .take(3)
+        .map(|load| load.to_owned())
+        .collect();
+    if loads.len() < 3 {
+        return Err(Error::parse(LOADAVG, "less than 3 load averages"));
+    }
+    Ok(loads)
+}
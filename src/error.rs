@@ -0,0 +1,144 @@
+use std::{error,fmt,io,panic};
+use std::path::{Path,PathBuf};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can go wrong in the bar, with enough context to tell
+/// where it went wrong.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading a file failed, e.g. one in `/sys` or `/proc`.
+    Read { path: PathBuf, source: io::Error },
+    /// A file was read, but its contents make no sense.
+    Parse { path: PathBuf, reason: ParseError },
+    /// The config file is broken.
+    Config { path: PathBuf, reason: String },
+    /// Writing the status line failed, i3bar is probably gone.
+    Output(io::Error),
+    /// Setting up a signal handler failed.
+    Signal(io::Error),
+    /// Talking to the sound server or mixer failed.
+    Audio(String),
+    /// Showing a desktop notification failed.
+    Notify(String),
+    /// Code panicked, with the panic's message.
+    Panic(String),
+    /// A thread of the bar is gone, the name says which one.
+    Disconnected(&'static str),
+    /// A block failed to update.
+    Block { name: String, source: Box<Error> },
+}
+
+/// What makes no sense in a file. The line ones carry the offending line.
+#[derive(Debug,Clone,PartialEq)]
+pub enum ParseError {
+    NoColon(String),
+    NoNumber(String),
+    UnknownUnit(String),
+    /// The value doesn't fit once scaled by its unit.
+    Overflow(String),
+    /// A key that has to be there isn't.
+    Missing(&'static str),
+    Other(String),
+}
+
+impl Error {
+    pub fn read<P: AsRef<Path>>(path: P, source: io::Error) -> Self {
+        Error::Read { path: path.as_ref().to_owned(), source }
+    }
+
+    pub fn parse<P: AsRef<Path>, R: ToString>(path: P, reason: R) -> Self {
+        Error::Parse {
+            path: path.as_ref().to_owned(),
+            reason: ParseError::Other(reason.to_string()),
+        }
+    }
+
+    /// Says which block the error happened in.
+    pub fn in_block(self, name: &str) -> Self {
+        Error::Block { name: name.to_owned(), source: Box::new(self) }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Read { path, source } => {
+                write!(f, "failed to read {}: {}", path.display(), source)
+            },
+            Error::Parse { path, reason } => {
+                write!(f, "failed to parse {}: {}", path.display(), reason)
+            },
+            Error::Config { path, reason } => {
+                write!(f, "broken config {}: {}", path.display(), reason)
+            },
+            Error::Output(source) => {
+                write!(f, "failed to write the status line: {}", source)
+            },
+            Error::Signal(source) => {
+                write!(f, "failed to handle signals: {}", source)
+            },
+            Error::Audio(reason) => write!(f, "audio: {}", reason),
+            Error::Notify(reason) => {
+                write!(f, "failed to notify: {}", reason)
+            },
+            Error::Panic(message) => write!(f, "panicked: {}", message),
+            Error::Disconnected(thread) => write!(f, "lost the {}", thread),
+            Error::Block { name, source } => {
+                write!(f, "block {}: {}", name, source)
+            },
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::NoColon(line) => write!(f, "no colon in line {:?}", line),
+            ParseError::NoNumber(line) => write!(f, "no number in line {:?}", line),
+            ParseError::UnknownUnit(line) => {
+                write!(f, "unknown unit in line {:?}", line)
+            },
+            ParseError::Overflow(line) => {
+                write!(f, "too large a value in line {:?}", line)
+            },
+            ParseError::Missing(key) => write!(f, "no {}", key),
+            ParseError::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Read { source, .. } |
+            Error::Output(source) |
+            Error::Signal(source) => Some(source),
+            Error::Block { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Runs `f`, turning a panic into an error.
+pub fn catch_panic<T, F: FnOnce() -> Result<T>>(f: F) -> Result<T> {
+    match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => {
+            let message = if let Some(message) = payload.downcast_ref::<&str>() {
+                (*message).to_owned()
+            } else if let Some(message) = payload.downcast_ref::<String>() {
+                message.clone()
+            } else {
+                "unknown cause".to_owned()
+            };
+            Err(Error::Panic(message))
+        },
+    }
+}
+
+/// Reports an error that the bar keeps running after. i3 passes stderr on
+/// to its log.
+pub fn log(error: &Error) {
+    eprintln!("mybar: {}", error);
+}
@@ -0,0 +1,297 @@
+/// One of a block's values.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Value {
+    Text(String),
+    /// Shown with `precision` digits after the point, unless the format
+    /// asks for something else.
+    Number { value: f64, precision: usize },
+    /// A number with a unit, e.g. `3.2 GiB`. The number is padded to `width`
+    /// and the format's precision applies to it, not to the unit.
+    Amount { value: f64, precision: usize, width: usize, unit: String },
+    /// Not known right now, e.g. the time left on a battery that is full.
+    Missing,
+}
+
+impl Value {
+    pub fn number(value: f64, precision: usize) -> Self {
+        Value::Number { value, precision }
+    }
+
+    /// A number without digits after the point by default.
+    pub fn whole(value: f64) -> Self {
+        Value::number(value, 0)
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::Text(text)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Value::Text(text.to_owned())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Missing, Into::into)
+    }
+}
+
+/// A block's values by name.
+pub type Values<'a> = [(&'a str, Value)];
+
+/// Fills the placeholders of a block's format with their values.
+///
+/// - `{key}` is replaced by the value of `key`. Unknown keys are left in
+///   place so typos show up on the bar.
+/// - `{key:>5}` pads the value to 5 characters, `<` aligns it left, `^`
+///   centers it. Without an alignment numbers go right and text goes left.
+/// - `{key:.2}` shows a number with 2 digits after the point, or cuts text
+///   after 2 characters. Amounts keep their unit. `{key:>6.1}` does both.
+/// - `[...]` is a section that vanishes if any value in it is missing, e.g.
+///   `bat {capacity}%[ {time}]`. Sections nest.
+/// - `\` takes the next character literally, e.g. `\[` or `\{`.
+pub fn render(template: &str, values: &Values) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    render_section(&mut rest, values, &mut out, false);
+    out
+}
+
+// Renders `rest` up to its end, or up to the `]` closing the section if
+// `nested`. Returns whether every value in it was there.
+fn render_section(
+    rest: &mut &str,
+    values: &Values,
+    out: &mut String,
+    nested: bool,
+) -> bool {
+    let mut complete = true;
+    while let Some(c) = rest.chars().next() {
+        *rest = &rest[c.len_utf8()..];
+        match c {
+            '\\' => {
+                if let Some(escaped) = rest.chars().next() {
+                    out.push(escaped);
+                    *rest = &rest[escaped.len_utf8()..];
+                }
+            },
+            '[' => {
+                let mut section = String::new();
+                if render_section(rest, values, &mut section, true) {
+                    out.push_str(&section);
+                }
+            },
+            ']' if nested => return complete,
+            '{' => {
+                let end = match rest.find('}') {
+                    Some(end) => end,
+                    None => {
+                        out.push(c);
+                        continue;
+                    },
+                };
+                let placeholder = &rest[..end];
+                *rest = &rest[end + 1..];
+                match render_placeholder(placeholder, values) {
+                    Some(Some(text)) => out.push_str(&text),
+                    Some(None) => complete = false,
+                    None => {
+                        out.push('{');
+                        out.push_str(placeholder);
+                        out.push('}');
+                    },
+                }
+            },
+            c => out.push(c),
+        }
+    }
+    complete
+}
+
+// `None` if there is no such key, `Some(None)` if its value is missing.
+fn render_placeholder(placeholder: &str, values: &Values) -> Option<Option<String>> {
+    let (key, spec) = match placeholder.split_once(':') {
+        Some((key, spec)) => (key, Spec::parse(spec)?),
+        None => (placeholder, Spec::default()),
+    };
+    let value = values.iter().find(|(name, _)| *name == key).map(|(_, value)| value)?;
+    Some(spec.apply(value))
+}
+
+#[derive(Clone,Copy,Default)]
+enum Align {
+    #[default]
+    Auto,
+    Left,
+    Right,
+    Center,
+}
+
+#[derive(Clone,Copy,Default)]
+struct Spec {
+    align: Align,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    // `[<>^][width][.precision]`, `None` if it is something else
+    fn parse(spec: &str) -> Option<Self> {
+        let mut rest = spec;
+        let align = match rest.chars().next() {
+            Some('<') => Align::Left,
+            Some('>') => Align::Right,
+            Some('^') => Align::Center,
+            _ => Align::Auto,
+        };
+        if !matches!(align, Align::Auto) {
+            rest = &rest[1..];
+        }
+        let (width, precision) = match rest.split_once('.') {
+            Some((width, precision)) => (width, Some(precision.parse().ok()?)),
+            None => (rest, None),
+        };
+        let width = match width {
+            "" => 0,
+            width => width.parse().ok()?,
+        };
+        Some(Spec { align, width, precision })
+    }
+
+    fn apply(&self, value: &Value) -> Option<String> {
+        let (text, align) = match value {
+            Value::Text(text) => {
+                let text = match self.precision {
+                    Some(length) => text.chars().take(length).collect(),
+                    None => text.clone(),
+                };
+                (text, Align::Left)
+            },
+            Value::Number { value, precision } => {
+                let precision = self.precision.unwrap_or(*precision);
+                (format!("{:.*}", precision, value), Align::Right)
+            },
+            Value::Amount { value, precision, width, unit } => {
+                let precision = self.precision.unwrap_or(*precision);
+                let text = format!(
+                    "{:>width$.precision$} {}",
+                    value,
+                    unit,
+                    width = width,
+                    precision = precision,
+                );
+                (text, Align::Right)
+            },
+            Value::Missing => return None,
+        };
+        let width = self.width;
+        let text = match self.align {
+            Align::Auto => match align {
+                Align::Right => format!("{:>width$}", text, width = width),
+                _ => format!("{:<width$}", text, width = width),
+            },
+            Align::Left => format!("{:<width$}", text, width = width),
+            Align::Right => format!("{:>width$}", text, width = width),
+            Align::Center => format!("{:^width$}", text, width = width),
+        };
+        Some(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> Vec<(&'static str, Value)> {
+        vec![
+            ("n", Value::number(4.5678, 1)),
+            ("t", Value::from("hello")),
+            ("a", Value::Amount {
+                value: 1.5,
+                precision: 1,
+                width: 0,
+                unit: "GiB".to_owned(),
+            }),
+            ("m", Value::from(None::<String>)),
+        ]
+    }
+
+    fn check(template: &str, expected: &str) {
+        assert_eq!(render(template, &values()), expected, "{:?}", template);
+    }
+
+    #[test]
+    fn placeholders() {
+        check("{n} {t} {a}", "4.6 hello 1.5 GiB");
+        check("no placeholders", "no placeholders");
+        check("", "");
+    }
+
+    #[test]
+    fn unknown_placeholders_stay() {
+        check("{nope}", "{nope}");
+        check("{n:bad}", "{n:bad}");
+        check("[{nope}]", "{nope}");
+    }
+
+    #[test]
+    fn missing_values_elide_their_section() {
+        check("{t}[ {m}]!", "hello!");
+        check("{t}{m}", "hello");
+        check("[{t} ][{m} ]", "hello ");
+    }
+
+    #[test]
+    fn sections() {
+        check("[]x[]", "x");
+        check("a[ b[ {m}] {n}]", "a b 4.6");
+        check("a[ {m}[ {n}]]", "a");
+        check("[[[{t}]]]", "hello");
+    }
+
+    #[test]
+    fn escapes() {
+        check(r"\[{n}\]", "[4.6]");
+        check(r"\{n\}", "{n}");
+        check(r"\\{n}", r"\4.6");
+        check(r"trailing\", "trailing");
+    }
+
+    #[test]
+    fn unterminated() {
+        check("a {n", "a {n");
+        check("a [{n}", "a 4.6");
+        check("a [{m}", "a ");
+        check("{n}]", "4.6]");
+    }
+
+    #[test]
+    fn precision_and_width() {
+        check("{n:.3}", "4.568");
+        check("{n:.0}", "5");
+        check("{n:6.2}", "  4.57");
+        check("{n:<6}", "4.6   ");
+        check("{n:^7}", "  4.6  ");
+        check("{t:.2}", "he");
+        check("{t:7}", "hello  ");
+        check("{t:>7.3}", "    hel");
+        check("{a:.2}", "1.50 GiB");
+        check("{a:10.0}", "     2 GiB");
+    }
+
+    #[test]
+    fn fixed_width_amounts() {
+        let amount = Value::Amount {
+            value: 12.0,
+            precision: 0,
+            width: 5,
+            unit: "B  ".to_owned(),
+        };
+        assert_eq!(render("{a}|", &[("a", amount)]), "   12 B  |");
+    }
+}
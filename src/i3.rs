@@ -3,24 +3,47 @@ extern crate serde;
 
 use std::{io,thread,time};
 use std::sync::mpsc;
-use std::io::{Write};
+use std::io::{Write,BufRead};
 use std::collections::{HashMap};
+use crate::{error,pause,scheduler};
+use crate::error::Error;
 use serde::{Serializer};
 use serde::ser::{SerializeSeq};
 
 #[derive(serde::Serialize)]
 struct I3BarInit {
     version: String,
+    click_events: bool,
+    stop_signal: i32,
+    cont_signal: i32,
+}
+
+pub const BUTTON_LEFT: u8 = 1;
+pub const BUTTON_MIDDLE: u8 = 2;
+pub const BUTTON_RIGHT: u8 = 3;
+pub const SCROLL_UP: u8 = 4;
+pub const SCROLL_DOWN: u8 = 5;
+
+enum Message {
+    Item(Box<Item>),
+    Reset,
+    // ends a batch of updates, `true` writes the line even if nothing changed
+    Commit(bool),
 }
 
 pub struct I3 {
     writer: io::Stdout,
     handle: Option<thread::JoinHandle<()>>,
-    transmitter: mpsc::Sender<Item>,
+    transmitter: mpsc::Sender<Message>,
 }
 
 impl I3 {
-    pub fn new () -> Result<I3, ()> {
+    /// Clicks are sent to `events`. After a commit the writer waits for
+    /// `coalesce` to pick up more updates before it writes the line.
+    pub fn new (
+        events: mpsc::Sender<scheduler::Event>,
+        coalesce: time::Duration,
+    ) -> error::Result<I3> {
         let (tx, rx) = mpsc::channel();
 
         let mut i3 = I3 {
@@ -28,70 +51,246 @@ impl I3 {
             handle: None,
             transmitter: tx,
         };
-        i3.init(rx)?;
+        i3.init(rx, coalesce)?;
+
+        thread::spawn(move || read_clicks(events));
 
         Ok(i3)
     }
 
-    pub fn send(&mut self, item: Item) -> Result<(), ()> {
-        match self.transmitter.send(item) {
+    pub fn send(&mut self, item: Item) -> error::Result<()> {
+        self.transmit(Message::Item(Box::new(item)))
+    }
+
+    /// Forgets all blocks, the next items sent define the new line.
+    pub fn reset(&mut self) -> error::Result<()> {
+        self.transmit(Message::Reset)
+    }
+
+    /// Writes a status line if any block changed since the last one.
+    pub fn commit(&mut self) -> error::Result<()> {
+        self.transmit(Message::Commit(false))
+    }
+
+    /// Writes a status line, even if it is the same as the last one.
+    pub fn emit(&mut self) -> error::Result<()> {
+        self.transmit(Message::Commit(true))
+    }
+
+    // fails once the writer gave up, it logged why
+    fn transmit(&mut self, message: Message) -> error::Result<()> {
+        match self.transmitter.send(message) {
             Ok(_) => Ok(()),
-            Err(_) => Err(()),
+            Err(_) => Err(Error::Disconnected("status line writer")),
         }
     }
 
-    fn init (&mut self, receiver: mpsc::Receiver<Item>) -> Result<(), ()> {
-        let init = I3BarInit { version: "1".to_owned() };
+    fn init (
+        &mut self,
+        receiver: mpsc::Receiver<Message>,
+        coalesce: time::Duration,
+    ) -> error::Result<()> {
+        let init = I3BarInit {
+            version: "1".to_owned(),
+            click_events: true,
+            stop_signal: pause::STOP_SIGNAL,
+            cont_signal: pause::CONT_SIGNAL,
+        };
         match serde_json::to_string(&init) {
             Ok(init_str) => {
-                self.write(&init_str.as_bytes())?;
-                flush();
+                self.write(init_str.as_bytes())?;
+                flush().map_err(Error::Output)?;
 
                 let handle: thread::JoinHandle<_> = thread::spawn(move || {
                     let mut serializer = serde_json::Serializer::new(io::stdout());
-                    let mut sequence = serializer.serialize_seq(None).unwrap();
-                    let mut state = HashMap::new();
-                    let mut line_keys: Vec<String> = vec![];
-
-                    loop {
-                        thread::sleep(time::Duration::new(1, 0));
-                        for item in receiver.try_iter() {
-                            if !&line_keys.contains(&item.name) {
-                                &line_keys.push(item.name.clone());
+                    let mut sequence = match serializer.serialize_seq(None) {
+                        Ok(sequence) => sequence,
+                        Err(err) => return error::log(&Error::Output(err.into())),
+                    };
+                    let mut line = Line::default();
+
+                    for message in receiver.iter() {
+                        let mut force = match message {
+                            Message::Commit(force) => force,
+                            message => {
+                                line.apply(message);
+                                continue;
+                            },
+                        };
+
+                        let deadline = time::Instant::now() + coalesce;
+                        loop {
+                            let timeout = deadline
+                                .saturating_duration_since(time::Instant::now());
+                            match receiver.recv_timeout(timeout) {
+                                Ok(Message::Commit(more_force)) => {
+                                    force = force || more_force;
+                                },
+                                Ok(message) => line.apply(message),
+                                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                            }
+                        }
+
+                        if force || line.changed {
+                            let written = sequence
+                                .serialize_element(&line.items())
+                                .map_err(io::Error::from)
+                                .and_then(|_| flush());
+                            if let Err(err) = written {
+                                return error::log(&Error::Output(err));
                             }
-                            state.insert(item.name.clone(), item);
+                            line.changed = false;
                         }
-                        let line: Vec<&Item> = (&line_keys)
-                            .into_iter()
-                            .map(|name| state.get(name).unwrap())
-                            .collect();
-                        sequence.serialize_element(&line).unwrap();
-                        flush();
                     }
                 });
 
                 self.handle = Some(handle);
                 Ok(())
             },
-            Err(_) => Err(()),
+            Err(err) => Err(Error::Output(err.into())),
         }
     }
 
-    fn write (&mut self, data: &[u8]) -> Result<(), ()> {
-        match self.writer.write(data) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(()),
+    fn write (&mut self, data: &[u8]) -> error::Result<()> {
+        self.writer.write_all(data).map_err(Error::Output)
+    }
+}
+
+// Blocks are told apart by name and instance.
+type Key = (String, Option<String>);
+
+// What the writer thread knows about the bar.
+#[derive(Default)]
+struct Line {
+    state: HashMap<Key, Item>,
+    keys: Vec<Key>,
+    changed: bool,
+}
+
+impl Line {
+    fn apply(&mut self, message: Message) {
+        match message {
+            Message::Item(item) => {
+                let key = (item.name.clone(), item.instance.clone());
+                if self.state.get(&key) == Some(&*item) {
+                    return;
+                }
+                if !self.keys.contains(&key) {
+                    self.keys.push(key.clone());
+                }
+                self.state.insert(key, *item);
+            },
+            Message::Reset => {
+                self.keys.clear();
+                self.state.clear();
+            },
+            Message::Commit(_) => return,
         }
+        self.changed = true;
+    }
+
+    fn items(&self) -> Vec<&Item> {
+        self.keys
+            .iter()
+            .map(|key| self.state.get(key).unwrap())
+            .collect()
     }
 }
 
-fn flush() {
-    std::io::stdout().write("\n".as_bytes()).unwrap();
+// i3bar sends an infinite JSON array on stdin, one event per line:
+// `[`, then `{...}`, then `,{...}` for every following event.
+fn read_clicks(transmitter: mpsc::Sender<scheduler::Event>) {
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let raw_event = line.trim().trim_start_matches(',');
+        if raw_event.is_empty() || raw_event == "[" {
+            continue;
+        }
+        if let Ok(event) = serde_json::from_str(raw_event) {
+            if transmitter.send(scheduler::Event::Click(event)).is_err() {
+                break;
+            }
+        }
+    }
 }
 
-#[derive(serde::Serialize)]
+fn flush() -> io::Result<()> {
+    std::io::stdout().write_all("\n".as_bytes())
+}
+
+#[derive(serde::Serialize,Default,PartialEq)]
 pub struct Item {
     pub name: String,
     pub full_text: String,
     pub color: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub short_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub border: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub border_top: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub border_right: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub border_bottom: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub border_left: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_width: Option<MinWidth>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub align: Option<Align>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub urgent: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub separator: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub separator_block_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub markup: Option<Markup>,
+}
+
+/// i3bar either takes a width in pixels or a string whose rendered width is
+/// used as the minimum.
+#[derive(serde::Serialize,serde::Deserialize,Clone,Debug,PartialEq)]
+#[serde(untagged)]
+pub enum MinWidth {
+    Pixels(u32),
+    Text(String),
+}
+
+#[derive(serde::Serialize,serde::Deserialize,Clone,Copy,Debug,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(serde::Serialize,serde::Deserialize,Clone,Copy,Debug,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Markup {
+    Pango,
+    None,
+}
+
+// not every block cares about every field of the event
+#[allow(dead_code)]
+#[derive(serde::Deserialize,Clone,Debug)]
+pub struct ClickEvent {
+    pub name: String,
+    pub instance: Option<String>,
+    pub button: u8,
+    #[serde(default)]
+    pub modifiers: Vec<String>,
+    pub x: i64,
+    pub y: i64,
 }
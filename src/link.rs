@@ -1,5 +1,11 @@
-use std::{time,fs,f64};
+use std::{time,fs};
 use std::str::FromStr;
+use std::rc::Rc;
+use std::cell::RefCell;
+use crate::{i3,format};
+use crate::units::{Units,UnitsConfig};
+use crate::error::{self,Error};
+use crate::block::{Block,Item};
 
 pub struct Link {
     last_check: Option<time::Instant>,
@@ -8,7 +14,7 @@ pub struct Link {
 }
 
 impl Link {
-    pub fn new() -> Result<Self, ()> {
+    pub fn new() -> error::Result<Self> {
         let ifaces = get_ifaces()?;
         Ok(Link {
             ifaces,
@@ -17,10 +23,10 @@ impl Link {
         })
     }
 
-    pub fn is_up(&self) -> Result<bool, ()> {
+    pub fn is_up(&self) -> error::Result<bool> {
         Ok(
-            (&self.ifaces)
-                .into_iter()
+            self.ifaces
+                .iter()
                 .any(|iface| {
                     let carrier_path = format!("{}/carrier", iface);
                     match fs::read_to_string(carrier_path) {
@@ -31,40 +37,34 @@ impl Link {
         )
     }
 
-    pub fn stats(&mut self) -> Result<LinkStats, ()> {
-        let stats = (&self.ifaces)
-            .into_iter()
+    pub fn stats(&mut self) -> error::Result<LinkStats> {
+        let stats = self.ifaces
+            .iter()
             .fold(LinkStats { upload: 0, download: 0 }, |mut stats, iface| {
-                let tx_path = get_tx_path(&iface);
-                let rx_path = get_rx_path(&iface);
+                let tx_path = get_tx_path(iface);
+                let rx_path = get_rx_path(iface);
                 let maybe_tx = read_transmition(&tx_path);
                 let maybe_rx = read_transmition(&rx_path);
-                if maybe_tx.is_ok() && maybe_rx.is_ok() {
-                    stats.upload += maybe_tx.unwrap();
-                    stats.download += maybe_rx.unwrap();
+                if let (Ok(tx), Ok(rx)) = (maybe_tx, maybe_rx) {
+                    stats.upload += tx;
+                    stats.download += rx;
                 }
                 stats
             });
 
+        let speed_stats = match (self.last_check, &self.last_state) {
+            (Some(last_check), Some(last_state)) => {
+                let passed = time::Instant::now()
+                    .duration_since(last_check)
+                    .as_millis() as f64;
 
-        let speed_stats;
-        if self.last_check.is_some() {
-            // XXX alternatives to using `clone` here?
-            let last_state = self.last_state.clone().unwrap();
-            let passed = time::Instant::now()
-                .duration_since(self.last_check.unwrap())
-                .as_millis() as f64;
-
-            let upload = get_rate(stats.upload, last_state.upload, passed);
-            let download = get_rate(stats.download, last_state.download, passed);
-
-            speed_stats = LinkStats {
-                upload: upload as i64,
-                download: download as i64,
-            };
-        } else {
-            speed_stats = LinkStats { upload: 0, download: 0 };
-        }
+                LinkStats {
+                    upload: get_rate(stats.upload, last_state.upload, passed),
+                    download: get_rate(stats.download, last_state.download, passed),
+                }
+            },
+            _ => LinkStats { upload: 0, download: 0 },
+        };
 
         self.last_check = Some(time::Instant::now());
         self.last_state = Some(stats);
@@ -74,10 +74,10 @@ impl Link {
 
 fn get_rate(current: i64, before: i64, passed_ms: f64) -> i64 {
     let diff = current as f64 - before as f64;
-    if diff > (0 as f64) {
-        let rate = diff / passed_ms * (1000 as f64);
+    if diff > 0.0 {
+        let rate = diff / passed_ms * 1000.0;
         rate as i64
-    } else { 0 as i64 }
+    } else { 0 }
 }
 
 #[derive(Clone,Debug)]
@@ -86,30 +86,6 @@ pub struct LinkStats {
     pub download: i64,
 }
 
-impl LinkStats {
-    pub fn pretty_upload(&self) -> String {
-        pretty_bytes(self.upload)
-    }
-
-    pub fn pretty_download(&self) -> String {
-        pretty_bytes(self.download)
-    }
-}
-
-fn pretty_bytes(bytes: i64) -> String {
-    if bytes > 999 {
-        let k_bytes = bytes as f64 / 1000 as f64;
-        if k_bytes > 999 as f64 {
-            let m_bytes = k_bytes / 1000 as f64;
-            format!("{:.*} MB", 1, m_bytes)
-        } else {
-            format!("{:.*} KB", 1, k_bytes)
-        }
-    } else {
-        format!("{} B", bytes)
-    }
-}
-
 fn get_tx_path(iface: &str) -> String {
     format!("{}/statistics/tx_bytes", iface)
 }
@@ -118,20 +94,20 @@ fn get_rx_path(iface: &str) -> String {
     format!("{}/statistics/rx_bytes", iface)
 }
 
-fn read_transmition(path: &str) -> Result<i64, ()> {
+fn read_transmition(path: &str) -> error::Result<i64> {
     // TODO: optimize this, by getting the inode only once
     match fs::read_to_string(path) {
         Ok(bytes) => {
-            match i64::from_str(&bytes.trim()) {
+            match i64::from_str(bytes.trim()) {
                 Ok(transmition) => Ok(transmition),
-                Err(_) => Err(())
+                Err(err) => Err(Error::parse(path, err)),
             }
         },
-        Err(_) => Err(())
+        Err(err) => Err(Error::read(path, err)),
     }
 }
 
-fn get_ifaces () -> Result<Vec<String>, ()> {
+fn get_ifaces () -> error::Result<Vec<String>> {
     match fs::read_dir("/sys/class/net") {
         Ok(dir) => Ok(
             dir
@@ -147,6 +123,107 @@ fn get_ifaces () -> Result<Vec<String>, ()> {
                 .filter(|iface| *iface != "/sys/class/net/lo")
                 .collect()
         ),
-        Err(_) => Err(()),
+        Err(err) => Err(Error::read("/sys/class/net", err)),
+    }
+}
+
+/// Shows whether any interface has a carrier.
+pub struct InetBlock {
+    net: Rc<RefCell<Link>>,
+    interval: time::Duration,
+    format: String,
+}
+
+impl InetBlock {
+    pub fn new(
+        net: Rc<RefCell<Link>>,
+        interval: Option<time::Duration>,
+        format: Option<String>,
+    ) -> Self {
+        InetBlock {
+            net,
+            interval: interval.unwrap_or(time::Duration::from_secs(1)),
+            format: format.unwrap_or_else(|| "{icon}".to_owned()),
+        }
+    }
+}
+
+impl Block for InetBlock {
+    fn name(&self) -> &str { "INet" }
+
+    fn interval(&self) -> time::Duration { self.interval }
+
+    fn update(&mut self) -> error::Result<Item> {
+        let good = self.net.borrow().is_up()?;
+
+        let icon = if good {
+            "\u{263C}".to_owned()
+        } else {
+            "\u{2694}".to_owned()
+        };
+
+        Ok(Item {
+            name: self.name().to_owned(),
+            text: format::render(&self.format, &[("icon", icon.into())]),
+            good: Some(good),
+            ..Default::default()
+        })
+    }
+}
+
+/// Upload and download rates summed over all interfaces.
+pub struct TrafficBlock {
+    net: Rc<RefCell<Link>>,
+    interval: time::Duration,
+    format: String,
+    units: Units,
+    min_width: String,
+}
+
+impl TrafficBlock {
+    pub fn new(
+        net: Rc<RefCell<Link>>,
+        interval: Option<time::Duration>,
+        format: Option<String>,
+        units: UnitsConfig,
+    ) -> Self {
+        let format = format.unwrap_or_else(|| {
+            "net \u{2191}{up} / \u{2193}{down}".to_owned()
+        });
+        let units = Units::default().with(units);
+        let widest = units.widest_rate();
+        let min_width = format::render(&format, &[
+            ("up", widest.clone()),
+            ("down", widest),
+        ]);
+        TrafficBlock {
+            net,
+            interval: interval.unwrap_or(time::Duration::from_secs(5)),
+            format,
+            units,
+            min_width,
+        }
+    }
+}
+
+impl Block for TrafficBlock {
+    fn name(&self) -> &str { "Traffic" }
+
+    fn interval(&self) -> time::Duration { self.interval }
+
+    fn update(&mut self) -> error::Result<Item> {
+        let stats = self.net.borrow_mut().stats()?;
+        Ok(Item {
+            name: self.name().to_owned(),
+            text: format::render(&self.format, &[
+                ("up", self.units.rate(stats.upload as f64)),
+                ("down", self.units.rate(stats.download as f64)),
+            ]),
+            good: None,
+            // keep the bar from jumping around as the rates change
+            min_width: Some(i3::MinWidth::Text(self.min_width.clone())),
+            align: Some(i3::Align::Right),
+            ..Default::default()
+        })
     }
 }
@@ -1,215 +1,222 @@
 extern crate serde;
 extern crate serde_json;
-extern crate chrono;
 
-use std::{time,thread,fs};
-use chrono::{Datelike,Timelike};
-use std::str::FromStr;
-use std::io;
+use std::{process,time};
+use std::sync::mpsc;
+use std::rc::Rc;
+use std::cell::RefCell;
+use config::BlockKind;
+use block::{Block,Item};
 
 mod i3;
 mod link;
 mod audio;
+#[cfg(feature = "alsa")]
+mod mixer;
 mod mem;
+mod pause;
+mod config;
+mod format;
+mod units;
+mod block;
+mod scheduler;
+mod battery;
+mod cpu;
+mod thermal;
+mod sysfs;
+mod clock;
+mod error;
+mod notify;
 
-struct Item {
-    name: String,
-    text: String,
-    good: Option<bool>,
-}
-
-impl Item {
-    fn to_i3_item (self: &Item) -> i3::Item {
-        let color;
-        if self.good.is_some() {
-            if self.good.unwrap() {
-                color = "#00FF00".to_owned();
-            } else {
-                color = "#FF0000".to_owned();
-            }
-        } else {
-            color = "#FFFFFF".to_owned();
-        }
-
-        i3::Item {
-            name: self.name.clone(),
-            full_text: self.text.clone(),
-            color,
-        }
+fn main() {
+    if let Err(err) = start_bar() {
+        error::log(&err);
+        process::exit(1);
     }
 }
 
-// todo use `?` short syntax
-fn main() {
-    start_bar().unwrap();
+/// Whatever has to outlive a config reload, e.g. the traffic counters.
+struct Shared {
+    net: Rc<RefCell<link::Link>>,
+    audio: Rc<RefCell<audio::Audio>>,
+    /// Battery thresholds notified about, so a reload doesn't repeat them.
+    notified: Rc<RefCell<Vec<u64>>>,
 }
 
-fn start_bar() -> Result<(), ()> {
-    let mut bar = i3::I3::new()?;
-    let mut net = link::Link::new()?;
-
-    let mut pulse = audio::Audio::new();
-    let memory = mem::Mem::new();
+fn start_bar() -> error::Result<()> {
+    // without a usable config we still want a bar, just the default one
+    let (config, mut config_ok) = match config::Config::load() {
+        Ok(config) => (config, true),
+        Err(err) => {
+            error::log(&err);
+            (config::Config::default(), false)
+        },
+    };
+    let (events, event_receiver) = mpsc::channel();
+    let pause = pause::Pause::new()?;
+    config::watch(events.clone(), pause.clone())?;
+    let mut bar = i3::I3::new(
+        events.clone(),
+        time::Duration::from_millis(config.coalesce),
+    )?;
+    let shared = Shared {
+        net: Rc::new(RefCell::new(link::Link::new()?)),
+        audio: Rc::new(RefCell::new(
+            audio::Audio::new(pause.clone(), events.clone(), &config.audio)?
+        )),
+        notified: Rc::new(RefCell::new(vec![])),
+    };
+    shared.audio.borrow_mut().pin(config.audio.sink.clone());
 
-    let mut i = 0;
+    let mut scheduler = build_scheduler(&config, &shared);
+    let mut reloaded = true;
     loop {
-        let inet = get_inet(&net)?.to_i3_item();
-        bar.send(inet)?;
-
-        if i % 5 == 0 {
-            let traffic = get_traffic(&mut net)?.to_i3_item();
-            bar.send(traffic)?;
+        let event = match scheduler.next_deadline() {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(time::Instant::now());
+                event_receiver.recv_timeout(timeout)
+            },
+            None => event_receiver.recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+
+        // while the bar is hidden there is nobody to show anything to
+        let resumed = pause.wait();
+
+        match event {
+            Ok(scheduler::Event::Click(event)) => {
+                scheduler.click(&event, &mut bar)?;
+            },
+            Ok(scheduler::Event::Update(name)) => {
+                scheduler.update(&name, &mut bar)?;
+            },
+            Ok(scheduler::Event::Reload) => {
+                // a broken config keeps the blocks we already have
+                match config::Config::load() {
+                    Ok(new_config) => {
+                        // the rest of `[audio]` needs a restart
+                        shared.audio.borrow_mut().pin(new_config.audio.sink.clone());
+                        scheduler = build_scheduler(&new_config, &shared);
+                        config_ok = true;
+                    },
+                    Err(err) => {
+                        error::log(&err);
+                        config_ok = false;
+                    },
+                }
+                bar.reset()?;
+                reloaded = true;
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err(error::Error::Disconnected("event channel"));
+            },
         }
 
-        let memory = memory.read()?;
-        bar.send(format_mem(memory))?;
-
-        bar.send(get_cpu())?;
-
-        let volume = get_volume(&mut pulse)?.to_i3_item();
-        bar.send(volume)?;
-
-        let volume = get_volume(&mut pulse)?.to_i3_item();
-        bar.send(volume)?;
-
-        let battery = read_battery()?.to_i3_item();
-        bar.send(battery)?;
+        if reloaded && !config_ok {
+            bar.send(config_error().to_i3_item())?;
+        }
 
-        let date_time = get_date_time()?.to_i3_item();
-        bar.send(date_time)?;
+        if resumed || reloaded {
+            scheduler.run_all(&mut bar)?;
+            bar.emit()?;
+        } else {
+            scheduler.run(&mut bar)?;
+            bar.commit()?;
+        }
 
-        i += 1;
-        thread::sleep(time::Duration::new(1, 0));
+        reloaded = false;
     }
 }
 
-fn read_battery() -> Result<Item, ()> {
-    match read_battery_p() {
-        Ok(item) => Ok(item),
-        Err(_) => Err(()),
+fn build_scheduler(
+    config: &config::Config,
+    shared: &Shared,
+) -> scheduler::Scheduler {
+    let mut scheduler = scheduler::Scheduler::new();
+    for block_config in &config.blocks {
+        let interval = block_config.interval();
+        let format = block_config.format.clone();
+        let block: Box<dyn Block> = match &block_config.kind {
+            BlockKind::Inet {} => Box::new(
+                link::InetBlock::new(Rc::clone(&shared.net), interval, format)
+            ),
+            BlockKind::Traffic {} => Box::new(
+                link::TrafficBlock::new(
+                    Rc::clone(&shared.net),
+                    interval,
+                    format,
+                    block_config.units,
+                )
+            ),
+            BlockKind::Memory { critical, pressure } => Box::new(
+                mem::MemoryBlock::new(
+                    interval,
+                    format,
+                    *critical,
+                    *pressure,
+                    block_config.units,
+                )
+            ),
+            BlockKind::Cpu { critical } => Box::new(
+                cpu::CpuBlock::new(interval, format, *critical)
+            ),
+            BlockKind::Thermal { sensor, critical, margin } => Box::new(
+                thermal::ThermalBlock::new(
+                    interval,
+                    format,
+                    sensor.clone(),
+                    *critical,
+                    *margin,
+                )
+            ),
+            BlockKind::Volume { step, max } => Box::new(audio::VolumeBlock::new(
+                Rc::clone(&shared.audio),
+                interval,
+                format,
+                *step,
+                *max,
+            )),
+            BlockKind::Microphone {} => Box::new(audio::MicrophoneBlock::new(
+                Rc::clone(&shared.audio),
+                interval,
+                format,
+            )),
+            BlockKind::Streams { step, max } => Box::new(audio::StreamsBlock::new(
+                Rc::clone(&shared.audio),
+                interval,
+                format,
+                *step,
+                *max,
+            )),
+            BlockKind::Battery { mode, batteries, low, high, critical, notify } => {
+                Box::new(battery::BatteryBlock::new(
+                    interval,
+                    format,
+                    *mode,
+                    batteries.clone(),
+                    battery::Thresholds {
+                        low: *low,
+                        high: *high,
+                        critical: *critical,
+                        notify: notify.clone(),
+                    },
+                    Rc::clone(&shared.notified),
+                ))
+            },
+            BlockKind::DateTime {} => Box::new(clock::ClockBlock::new(interval, format)),
+        };
+        scheduler.add(block, block_config.style());
     }
+    scheduler
 }
 
-fn read_battery_p() -> io::Result<Item> {
-    let capacity_path = "/sys/class/power_supply/BAT0/capacity";
-    let charging_path = "/sys/class/power_supply/ADP1/online";
-
-    let capacity_str = fs::read_to_string(capacity_path)?;
-    let capacity = u64::from_str(capacity_str.trim()).unwrap();
-
-    let charging_str = fs::read_to_string(charging_path)?;
-    let is_charging = charging_str.trim() == "1";
-
-    let good = if is_charging {
-        Some(true)
-    } else if capacity < 30 {
-        Some(false)
-    } else if capacity > 70 {
-        Some(true)
-    } else {
-        None
-    };
-
-    let affix =
-        if is_charging { "\u{1f5f2} ".to_owned() }
-        else { "%".to_owned() };
-
-    Ok(Item {
-        name: "Battery".to_owned(),
-        text: format!("bat {}{}", capacity, affix),
-        good,
-    })
-}
-
-fn get_inet(net: &link::Link) -> Result<Item, ()> {
-    let text;
-    let good = net.is_up()?;
-
-    if good {
-        text = "\u{263C}".to_owned();
-    } else {
-        text = "\u{2694}".to_owned();
+fn config_error() -> Item {
+    Item {
+        name: "Config".to_owned(),
+        text: "config error".to_owned(),
+        good: Some(false),
+        urgent: Some(true),
+        ..Default::default()
     }
-
-    Ok(Item {
-        name: "INet".to_owned(),
-        text,
-        good: Some(good),
-    })
-}
-
-fn get_traffic(net: &mut link::Link) -> Result<Item, ()> {
-    let stats = net.stats()?;
-    Ok(Item {
-        name: "Traffic".to_owned(),
-        text: format!(
-            "net \u{2191}{} / \u{2193}{}",
-            stats.pretty_upload(),
-            stats.pretty_download()
-        ).to_owned(),
-        good: None,
-    })
-}
-
-// TODO: better error handling
-fn get_date_time() -> Result<Item, ()> {
-    let now = chrono::Local::now();
-
-    let item = Item {
-        name: "DateTime".to_owned(),
-        text: format!(
-            "{}.{:0>2}.{:0>2} {:0>2}.{:0>2}",
-            now.year(),
-            now.month(),
-            now.day(),
-            now.hour(),
-            now.minute(),
-        ).to_owned(),
-        good: None,
-    };
-
-    Ok(item)
-}
-
-fn get_volume(pulse: &mut audio::Audio) -> Result<Item, ()> {
-    let volume = pulse.get_volume();
-    Ok(Item {
-        name: "AudioVolume".to_owned(),
-        text: format!("aud {} %", volume).to_owned(),
-        good: None,
-    })
-}
-
-fn format_mem_amount(amount: u64) -> f64 {
-    amount as f64 / 1_000_000 as f64
-}
-
-fn format_mem(info: mem::MemInfo) -> i3::Item {
-    let used = format_mem_amount(info.used);
-    let total = format_mem_amount(info.total);
-    let good = if used / total > 0.8 {
-        Some(false)
-    } else {
-        None
-    };
-    let item = Item {
-        name: "Memory".to_owned(),
-        text: format!("mem {:.*}/{:.*}", 1, used, 1, total).to_owned(),
-        good,
-    };
-    item.to_i3_item()
-}
-
-// TODO: who cares abour errors?!
-fn get_cpu() -> i3::Item {
-    let stats = fs::read_to_string("/proc/loadavg").unwrap();
-    let mut split = stats.split(" ");
-    let min5 = split.nth(1).unwrap();
-    let item = Item {
-        name: "CPU Load Average".to_owned(),
-        text: format!("cpu {}", min5).to_owned(),
-        // TODO
-        good: None,
-    };
-    item.to_i3_item()
 }
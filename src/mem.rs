@@ -1,98 +1,429 @@
-use std::io;
-use std::str;
-use std::io::{Read};
-use std::fs::{File};
+use std::{fs,io,str};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
 use std::str::FromStr;
+use std::time;
+use crate::format::{self,Value};
+use crate::units::{Prefix,Units,UnitsConfig};
+use crate::error::{self,Error,ParseError};
+use crate::block::{Block,Item};
 
-pub struct Mem {}
+const MEMINFO: &str = "/proc/meminfo";
+const PRESSURE: &str = "/proc/pressure/memory";
 
-#[derive(Debug)]
+// big enough for `/proc/meminfo` on current kernels, grows if it isn't
+const BUFFER_SIZE: usize = 4096;
+
+/// Reads `/proc/meminfo` through a file that stays open and a buffer that is
+/// reused, so updates don't allocate.
+pub struct Mem {
+    file: Option<File>,
+    buf: Vec<u8>,
+}
+
+/// What `/proc/meminfo` says, every amount in bytes. Keys missing on older
+/// kernels are 0.
+#[derive(Debug,Default)]
 pub struct MemInfo {
+    pub total: u64,
     pub free: u64,
-    pub used: u64,
+    pub available: u64,
+    pub buffers: u64,
+    pub cached: u64,
+    pub shmem: u64,
+    pub dirty: u64,
+    pub swap_total: u64,
+    pub swap_free: u64,
+    pub swap_cached: u64,
+    /// Counted in pages of `hugepage_size`.
+    pub hugepages_total: u64,
+    pub hugepages_free: u64,
+    pub hugepage_size: u64,
+}
+
+impl MemInfo {
+    /// What can't be given to new programs without swapping.
+    pub fn used(&self) -> u64 {
+        self.total.saturating_sub(self.available)
+    }
+
+    pub fn swap_used(&self) -> u64 {
+        self.swap_total.saturating_sub(self.swap_free)
+    }
+
+    fn field(&mut self, key: &[u8]) -> Option<&mut u64> {
+        let field = match key {
+            b"MemTotal" => &mut self.total,
+            b"MemFree" => &mut self.free,
+            b"MemAvailable" => &mut self.available,
+            b"Buffers" => &mut self.buffers,
+            b"Cached" => &mut self.cached,
+            b"Shmem" => &mut self.shmem,
+            b"Dirty" => &mut self.dirty,
+            b"SwapTotal" => &mut self.swap_total,
+            b"SwapFree" => &mut self.swap_free,
+            b"SwapCached" => &mut self.swap_cached,
+            b"HugePages_Total" => &mut self.hugepages_total,
+            b"HugePages_Free" => &mut self.hugepages_free,
+            b"Hugepagesize" => &mut self.hugepage_size,
+            _ => return None,
+        };
+        Some(field)
+    }
+}
+
+/// One line of a pressure stall file: the share of time in percent that
+/// tasks were stalled, averaged over 10, 60 and 300 seconds, and the total
+/// stall time in microseconds.
+#[derive(Debug,Default,Clone,Copy)]
+pub struct Stall {
+    pub avg10: f64,
+    pub avg60: f64,
+    pub avg300: f64,
     pub total: u64,
 }
 
+/// Memory pressure from `/proc/pressure/memory`. `some` counts time in which
+/// at least one task waited for memory, `full` time in which all did.
+#[derive(Debug,Default,Clone,Copy)]
+pub struct Pressure {
+    pub some: Stall,
+    pub full: Stall,
+}
+
 impl Mem {
     pub fn new() -> Self {
-        Mem { }
+        Mem { file: None, buf: Vec::with_capacity(BUFFER_SIZE) }
     }
 
-    pub fn read(&self) -> Result<MemInfo, ()> {
-        match self.p_read() {
-            Ok(meminfo) => Ok(meminfo),
-            Err(_) => Err(())
+    pub fn read(&mut self) -> error::Result<MemInfo> {
+        if let Err(err) = self.fill() {
+            // open it again next time, in case it was the file that broke
+            self.file = None;
+            return Err(Error::read(MEMINFO, err));
         }
+        parse_meminfo(&self.buf)
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => self.file.insert(File::open(MEMINFO)?),
+        };
+        read_all(file, &mut self.buf)
     }
 
-    fn p_read(&self) -> io::Result<MemInfo> {
-        let mut meminfo_file = File::open("/proc/meminfo")?;
-        let mut free_opt = None;
-        let mut total_opt = None;
-
-        let mut chunk_buf = Vec::with_capacity(256);
-        while free_opt.is_none() && total_opt.is_none() {
-            let mut buf = [0; 128];
-            let red_bytes = meminfo_file.read(&mut buf)?;
-            if red_bytes == 0 {
-                return Err(
-                    io::Error::new(io::ErrorKind::Other, "Data not found")
-                );
-            }
-
-            chunk_buf.extend_from_slice(&buf);
-            let meminfo_chunk = str::from_utf8(&chunk_buf).unwrap();
-            let mut sub_meminfo_chunk = &meminfo_chunk[..];
-            let mut last_idx = 0;
-            loop {
-                let idx_res = sub_meminfo_chunk.find('\n');
-                if idx_res.is_none() { break }
-                let idx = idx_res.unwrap();
-                let raw_line = sub_meminfo_chunk.get(0..idx);
-                let line = parse_meminfo_line(raw_line)?;
-                if line.key == "MemAvailable" {
-                    free_opt = Some(line.value);
-                } else if line.key == "MemTotal" {
-                    total_opt = Some(line.value);
-                }
-                let new_idx = last_idx + idx + 1;
-                sub_meminfo_chunk = &meminfo_chunk[new_idx..];
-                last_idx = new_idx;
-            }
-            let new_buf = sub_meminfo_chunk.as_bytes().to_vec();
-            chunk_buf = new_buf;
+    /// `None` on kernels built without pressure stall information.
+    pub fn read_pressure(&self) -> error::Result<Option<Pressure>> {
+        let pressure = match fs::read_to_string(PRESSURE) {
+            Ok(pressure) => pressure,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(None);
+            },
+            Err(err) => return Err(Error::read(PRESSURE, err)),
+        };
+        let mut stalls = Pressure::default();
+        for line in pressure.lines() {
+            let mut columns = line.split_whitespace();
+            let stall = match columns.next() {
+                Some("some") => &mut stalls.some,
+                Some("full") => &mut stalls.full,
+                _ => continue,
+            };
+            *stall = parse_stall(columns)?;
         }
+        Ok(Some(stalls))
+    }
+}
 
-        let free = free_opt.unwrap();
-        let total = total_opt.unwrap();
-        let used = total - free;
-        Ok(MemInfo {
-            total,
-            free,
-            used
-        })
+// Reads the whole file from the start into `buf`, growing it if needed.
+// Every read that starts at offset 0 gets a fresh snapshot from the kernel.
+fn read_all(file: &File, buf: &mut Vec<u8>) -> io::Result<()> {
+    buf.clear();
+    loop {
+        if buf.len() == buf.capacity() {
+            buf.reserve(BUFFER_SIZE);
+        }
+        let filled = buf.len();
+        buf.resize(buf.capacity(), 0);
+        let read = file.read_at(&mut buf[filled..], filled as u64);
+        // only keep what was actually read
+        buf.truncate(filled + *read.as_ref().unwrap_or(&0));
+        match read {
+            Ok(0) => return Ok(()),
+            Ok(_) => {},
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Lines look like `MemTotal:       16314836 kB`, huge page counts come
+// without a unit.
+fn parse_meminfo(meminfo: &[u8]) -> error::Result<MemInfo> {
+    let mut info = MemInfo::default();
+    let mut found_total = false;
+    let mut found_available = false;
+
+    for line in meminfo.split(|&byte| byte == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        let colon = line
+            .iter()
+            .position(|&byte| byte == b':')
+            .ok_or_else(|| meminfo_error(ParseError::NoColon, line))?;
+        let key = &line[..colon];
+        found_total |= key == b"MemTotal";
+        found_available |= key == b"MemAvailable";
+        let field = match info.field(key) {
+            Some(field) => field,
+            None => continue,
+        };
+
+        let mut words = line[colon + 1..]
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|word| !word.is_empty());
+        let value = words
+            .next()
+            .and_then(|value| str::from_utf8(value).ok())
+            .and_then(|value| u64::from_str(value).ok())
+            .ok_or_else(|| meminfo_error(ParseError::NoNumber, line))?;
+        let scale = match words.next() {
+            None => 1,
+            Some(b"kB") => 1024,
+            Some(_) => return Err(meminfo_error(ParseError::UnknownUnit, line)),
+        };
+        *field = value
+            .checked_mul(scale)
+            .ok_or_else(|| meminfo_error(ParseError::Overflow, line))?;
+    }
+
+    // `MemAvailable` is missing on kernels before 3.14
+    let required = [(found_total, "MemTotal"), (found_available, "MemAvailable")];
+    for (found, key) in required {
+        if !found {
+            return Err(Error::Parse {
+                path: MEMINFO.into(),
+                reason: ParseError::Missing(key),
+            });
+        }
+    }
+    Ok(info)
+}
+
+fn meminfo_error(reason: fn(String) -> ParseError, line: &[u8]) -> Error {
+    Error::Parse {
+        path: MEMINFO.into(),
+        reason: reason(String::from_utf8_lossy(line).into_owned()),
+    }
+}
+
+// `avg10=0.12 avg60=0.05 avg300=0.01 total=123456`
+fn parse_stall<'a, I: Iterator<Item = &'a str>>(columns: I) -> error::Result<Stall> {
+    let mut stall = Stall::default();
+    for column in columns {
+        let (key, value) = column
+            .split_once('=')
+            .ok_or_else(|| Error::parse(PRESSURE, format!("no value in {}", column)))?;
+        let avg = match key {
+            "avg10" => &mut stall.avg10,
+            "avg60" => &mut stall.avg60,
+            "avg300" => &mut stall.avg300,
+            "total" => {
+                stall.total = u64::from_str(value)
+                    .map_err(|err| Error::parse(PRESSURE, err))?;
+                continue;
+            },
+            _ => continue,
+        };
+        *avg = f64::from_str(value).map_err(|err| Error::parse(PRESSURE, err))?;
     }
+    Ok(stall)
 }
 
-struct MemInfoLine {
-    key: String,
-    value: u64,
+/// Memory usage from `/proc/meminfo`. `{used}`, `{total}`, `{free}`,
+/// `{available}`, `{buffers}`, `{cached}`, `{shmem}`, `{dirty}`,
+/// `{swap_used}`, `{swap_total}`, `{swap_free}` and `{swap_cached}` are
+/// amounts in IEC units by default, `{hugepages_total}` and
+/// `{hugepages_free}` count huge pages. `{pressure}` and `{pressure_full}`
+/// are the `some` and `full` memory pressure over the last 10 seconds, in
+/// percent. The block turns red once the used share passes `critical`, or
+/// if `pressure` is set, once the `some` pressure reaches it. Without
+/// pressure information from the kernel the used share decides.
+pub struct MemoryBlock {
+    mem: Mem,
+    interval: time::Duration,
+    format: String,
+    critical: f64,
+    pressure: Option<f64>,
+    // only then pressure is read at all
+    wants_pressure: bool,
+    units: Units,
+}
+
+impl MemoryBlock {
+    pub fn new(
+        interval: Option<time::Duration>,
+        format: Option<String>,
+        critical: f64,
+        pressure: Option<f64>,
+        units: UnitsConfig,
+    ) -> Self {
+        let format = format.unwrap_or_else(|| "mem {used}/{total}".to_owned());
+        MemoryBlock {
+            mem: Mem::new(),
+            interval: interval.unwrap_or(time::Duration::from_secs(1)),
+            wants_pressure: pressure.is_some() || format.contains("{pressure"),
+            format,
+            critical,
+            pressure,
+            units: Units { prefix: Prefix::Iec, ..Units::default() }.with(units),
+        }
+    }
+}
+
+impl Block for MemoryBlock {
+    fn name(&self) -> &str { "Memory" }
+
+    fn interval(&self) -> time::Duration { self.interval }
+
+    fn update(&mut self) -> error::Result<Item> {
+        let info = self.mem.read()?;
+        // kernels booted with `psi=0` fail the read, the used share decides then
+        let pressure = if self.wants_pressure {
+            self.mem.read_pressure().unwrap_or(None)
+        } else {
+            None
+        };
+        let high = match (self.pressure, pressure) {
+            (Some(limit), Some(pressure)) => pressure.some.avg10 >= limit,
+            _ => info.used() as f64 / info.total as f64 > self.critical,
+        };
+        let amount = |amount| self.units.bytes(amount as f64);
+        let stall = |stall: fn(&Pressure) -> Stall| Value::from(
+            pressure.map(|pressure| Value::whole(stall(&pressure).avg10))
+        );
+        Ok(Item {
+            name: self.name().to_owned(),
+            text: format::render(&self.format, &[
+                ("used", amount(info.used())),
+                ("total", amount(info.total)),
+                ("free", amount(info.free)),
+                ("available", amount(info.available)),
+                ("buffers", amount(info.buffers)),
+                ("cached", amount(info.cached)),
+                ("shmem", amount(info.shmem)),
+                ("dirty", amount(info.dirty)),
+                ("swap_used", amount(info.swap_used())),
+                ("swap_total", amount(info.swap_total)),
+                ("swap_free", amount(info.swap_free)),
+                ("swap_cached", amount(info.swap_cached)),
+                ("hugepages_total", Value::whole(info.hugepages_total as f64)),
+                ("hugepages_free", Value::whole(info.hugepages_free as f64)),
+                ("pressure", stall(|pressure| pressure.some)),
+                ("pressure_full", stall(|pressure| pressure.full)),
+            ]),
+            good: if high { Some(false) } else { None },
+            ..Default::default()
+        })
+    }
 }
 
-fn parse_meminfo_line(raw: Option<&str>) -> io::Result<MemInfoLine> {
-    if raw.is_none() {
-        return Err(io::Error::new(io::ErrorKind::Other, "No line found"));
-    }
-    let line = raw.unwrap();
-    let mut key_value_pair = line.trim().split(':');
-    let key = key_value_pair.nth(0).unwrap();
-    let raw_value = key_value_pair.nth(0).unwrap();
-    let str_value = raw_value.trim()
-        // split away unit
-        .split(' ').nth(0).unwrap();
-    let value = u64::from_str(str_value).unwrap();
-    Ok(MemInfoLine {
-        key: key.to_string(),
-        value,
-    })
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const MEMINFO_SAMPLE: &str = "\
+MemTotal:       16314836 kB
+MemFree:         1302580 kB
+MemAvailable:    9876544 kB
+Buffers:          412340 kB
+Cached:          7531200 kB
+SwapTotal:       2097148 kB
+SwapFree:        2097148 kB
+HugePages_Total:       4
+Hugepagesize:       2048 kB
+";
+
+    fn parse_error(meminfo: &str) -> ParseError {
+        match parse_meminfo(meminfo.as_bytes()) {
+            Err(Error::Parse { reason, .. }) => reason,
+            result => panic!("expected a parse error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn parses_amounts_in_bytes() {
+        let info = parse_meminfo(MEMINFO_SAMPLE.as_bytes()).unwrap();
+        assert_eq!(info.total, 16314836 * 1024);
+        assert_eq!(info.available, 9876544 * 1024);
+        assert_eq!(info.used(), (16314836 - 9876544) * 1024);
+        assert_eq!(info.swap_used(), 0);
+        // counts have no unit
+        assert_eq!(info.hugepages_total, 4);
+        assert_eq!(info.hugepage_size, 2048 * 1024);
+    }
+
+    #[test]
+    fn missing_available() {
+        let meminfo = MEMINFO_SAMPLE.replace("MemAvailable:    9876544 kB\n", "");
+        assert_eq!(parse_error(&meminfo), ParseError::Missing("MemAvailable"));
+    }
+
+    #[test]
+    fn line_without_colon() {
+        let meminfo = format!("{}Cached 12 kB\n", MEMINFO_SAMPLE);
+        let line = "Cached 12 kB".to_owned();
+        assert_eq!(parse_error(&meminfo), ParseError::NoColon(line));
+    }
+
+    #[test]
+    fn unknown_unit() {
+        let meminfo = MEMINFO_SAMPLE.replace("412340 kB", "412340 MB");
+        assert!(matches!(parse_error(&meminfo), ParseError::UnknownUnit(_)));
+    }
+
+    #[test]
+    fn overflowing_value() {
+        let huge = format!("{} kB", u64::MAX / 2);
+        let meminfo = MEMINFO_SAMPLE.replace("412340 kB", &huge);
+        assert!(matches!(parse_error(&meminfo), ParseError::Overflow(_)));
+
+        let meminfo = MEMINFO_SAMPLE.replace("412340", "99999999999999999999");
+        assert!(matches!(parse_error(&meminfo), ParseError::NoNumber(_)));
+    }
+
+    #[test]
+    fn truncated_last_line() {
+        // a complete last line without its newline is fine
+        let meminfo = MEMINFO_SAMPLE.trim_end();
+        assert!(parse_meminfo(meminfo.as_bytes()).is_ok());
+
+        let meminfo = format!("{}Dirty:", MEMINFO_SAMPLE);
+        assert_eq!(parse_error(&meminfo), ParseError::NoNumber("Dirty:".to_owned()));
+    }
+
+    #[test]
+    fn reads_beyond_the_initial_capacity() {
+        let path = std::env::temp_dir()
+            .join(format!("mybar-meminfo-{}", std::process::id()));
+        let mut contents = MEMINFO_SAMPLE.to_owned();
+        while contents.len() <= 2 * BUFFER_SIZE {
+            contents.push_str("Unevictable:       12345 kB\n");
+        }
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+
+        let file = File::open(&path).unwrap();
+        let mut buf = Vec::with_capacity(BUFFER_SIZE);
+        read_all(&file, &mut buf).unwrap();
+        // a second read starts over instead of appending
+        read_all(&file, &mut buf).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(buf, contents.as_bytes());
+        let info = parse_meminfo(&buf).unwrap();
+        assert_eq!(info.total, 16314836 * 1024);
+    }
 }
@@ -0,0 +1,190 @@
+extern crate alsa;
+
+use std::io::Read;
+use std::time;
+use std::os::unix::io::AsRawFd;
+use alsa::mixer::{Mixer,Selem,SelemId,SelemChannelId};
+use alsa::poll::{self,Descriptors};
+use crate::audio::{Command,Exit,Microphone,State,Volume,Worker};
+use crate::error::{self,Error};
+
+// tried in this order when no control was configured
+const PLAYBACK_CONTROLS: [&str; 2] = ["Master", "PCM"];
+const CAPTURE_CONTROL: &str = "Capture";
+
+/// Follows the ALSA mixer of `device` until the bar is gone. ALSA has no
+/// sinks, so pinning and switching them does nothing. `control` is the simple
+/// mixer element that shows the volume. There are no streams either. With
+/// `retry` it returns after that long instead, see `Exit::Retry`.
+pub fn run(
+    worker: &mut Worker,
+    device: &str,
+    control: Option<&str>,
+    retry: Option<time::Duration>,
+) -> error::Result<Exit> {
+    worker.wakeup.set_nonblocking(true)
+        .map_err(|err| Error::Audio(err.to_string()))?;
+    let mixer = Mixer::new(device, true).map_err(mixer_error)?;
+    let controls = match control {
+        Some(control) => vec![control],
+        None => PLAYBACK_CONTROLS.to_vec(),
+    };
+    let playback = controls
+        .into_iter()
+        .find(|name| mixer.find_selem(&SelemId::new(name, 0)).is_some())
+        .ok_or_else(|| Error::Audio("ALSA: no playback control".to_owned()))?;
+    let deadline = retry.map(|retry| time::Instant::now() + retry);
+
+    loop {
+        worker.pause.wait();
+        for command in worker.commands() {
+            apply(&mixer, playback, command)?;
+        }
+
+        let state = State {
+            connected: true,
+            volume: read_playback(&mixer, playback)?,
+            microphone: Microphone {
+                volume: read_capture(&mixer)?,
+                // the mixer can't tell
+                recordings: 0,
+            },
+            streams: vec![],
+        };
+        if worker.report(state).is_err() {
+            return Ok(Exit::BarGone);
+        }
+
+        if !wait(&mixer, worker, deadline)? {
+            return Ok(Exit::Retry);
+        }
+    }
+}
+
+fn apply(mixer: &Mixer, playback: &str, command: Command) -> error::Result<()> {
+    let selem = find(mixer, playback)?;
+    match command {
+        Command::SetVolume(percent) => set_percent(&selem, percent)?,
+        Command::StepVolume(step, max) => {
+            let current = i64::from(playback_percent(&selem)?);
+            let target = (current + i64::from(step))
+                .max(0)
+                .min(i64::from(max));
+            set_percent(&selem, target as u32)?;
+        },
+        Command::ToggleMute => {
+            let on = selem.get_playback_switch(SelemChannelId::mono())
+                .map_err(mixer_error)?;
+            selem.set_playback_switch_all(if on == 0 { 1 } else { 0 })
+                .map_err(mixer_error)?;
+        },
+        Command::ToggleSourceMute => {
+            if let Ok(capture) = find(mixer, CAPTURE_CONTROL) {
+                let on = capture.get_capture_switch(SelemChannelId::mono())
+                    .map_err(mixer_error)?;
+                capture.set_capture_switch_all(if on == 0 { 1 } else { 0 })
+                    .map_err(mixer_error)?;
+            }
+        },
+        Command::Pin(_) |
+        Command::NextSink |
+        Command::StepStreamVolume(..) => {},
+    }
+    Ok(())
+}
+
+fn read_playback(mixer: &Mixer, playback: &str) -> error::Result<Volume> {
+    let selem = find(mixer, playback)?;
+    let mute = selem.has_playback_switch()
+        && selem.get_playback_switch(SelemChannelId::mono())
+            .map_err(mixer_error)? == 0;
+    Ok(Volume {
+        percent: playback_percent(&selem)?,
+        mute,
+        description: Some(playback.to_owned()),
+    })
+}
+
+// Machines without a capture control simply have no microphone.
+fn read_capture(mixer: &Mixer) -> error::Result<Volume> {
+    let selem = match find(mixer, CAPTURE_CONTROL) {
+        Ok(selem) => selem,
+        Err(_) => return Ok(Volume::default()),
+    };
+    let (min, max) = selem.get_capture_volume_range();
+    let raw = selem.get_capture_volume(SelemChannelId::mono())
+        .map_err(mixer_error)?;
+    let mute = selem.has_capture_switch()
+        && selem.get_capture_switch(SelemChannelId::mono())
+            .map_err(mixer_error)? == 0;
+    Ok(Volume {
+        percent: percent(raw, min, max),
+        mute,
+        description: Some(CAPTURE_CONTROL.to_owned()),
+    })
+}
+
+fn find<'a>(mixer: &'a Mixer, name: &str) -> error::Result<Selem<'a>> {
+    mixer.find_selem(&SelemId::new(name, 0))
+        .ok_or_else(|| Error::Audio(format!("ALSA: no {} control", name)))
+}
+
+fn playback_percent(selem: &Selem) -> error::Result<u32> {
+    let (min, max) = selem.get_playback_volume_range();
+    let raw = selem.get_playback_volume(SelemChannelId::mono())
+        .map_err(mixer_error)?;
+    Ok(percent(raw, min, max))
+}
+
+fn set_percent(selem: &Selem, percent: u32) -> error::Result<()> {
+    let (min, max) = selem.get_playback_volume_range();
+    // the hardware can't go beyond its maximum
+    let percent = i64::from(percent.min(100));
+    let raw = min + ((max - min) * percent + 50) / 100;
+    selem.set_playback_volume_all(raw).map_err(mixer_error)
+}
+
+// mixer volumes are steps between `min` and `max`, like amixer shows them
+fn percent(raw: i64, min: i64, max: i64) -> u32 {
+    if max <= min {
+        return 0;
+    }
+    // rounded, not truncated
+    (((raw - min) * 100 + (max - min) / 2) / (max - min)) as u32
+}
+
+// Blocks until the mixer changed or a command came in, `false` if the
+// deadline passed first.
+fn wait(
+    mixer: &Mixer,
+    worker: &Worker,
+    deadline: Option<time::Instant>,
+) -> error::Result<bool> {
+    let mut fds = mixer.get().map_err(mixer_error)?;
+    fds.push(poll::pollfd {
+        fd: worker.wakeup.as_raw_fd(),
+        events: poll::Flags::IN.bits(),
+        revents: 0,
+    });
+    let timeout = match deadline {
+        Some(deadline) => {
+            let left = deadline.saturating_duration_since(time::Instant::now());
+            left.as_millis().min(i32::MAX as u128) as i32
+        },
+        None => -1,
+    };
+    if poll::poll(&mut fds, timeout).map_err(mixer_error)? == 0 {
+        return Ok(false);
+    }
+
+    let mut buf = [0; 64];
+    while let Ok(read) = (&worker.wakeup).read(&mut buf) {
+        if read == 0 { break }
+    }
+    mixer.handle_events().map_err(mixer_error)?;
+    Ok(true)
+}
+
+fn mixer_error(err: alsa::Error) -> Error {
+    Error::Audio(format!("ALSA: {}", err))
+}
@@ -0,0 +1,54 @@
+#[cfg(feature = "dbus")]
+extern crate dbus;
+
+use crate::error::{self,Error};
+
+/// How much a notification should stand out, as the spec defines it.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Urgency {
+    Normal = 1,
+    Critical = 2,
+}
+
+/// Shows a desktop notification over the freedesktop Notifications D-Bus
+/// interface.
+#[cfg(feature = "dbus")]
+pub fn send(summary: &str, body: &str, urgency: Urgency) -> error::Result<()> {
+    use std::time;
+    use dbus::arg::{PropMap,RefArg,Variant};
+    use dbus::blocking::Connection;
+
+    let notify_error = |err: dbus::Error| Error::Notify(err.to_string());
+    let connection = Connection::new_session().map_err(notify_error)?;
+    let proxy = connection.with_proxy(
+        "org.freedesktop.Notifications",
+        "/org/freedesktop/Notifications",
+        time::Duration::from_secs(5),
+    );
+    let mut hints = PropMap::new();
+    hints.insert(
+        "urgency".to_owned(),
+        Variant(Box::new(urgency as u8) as Box<dyn RefArg>),
+    );
+    let actions: Vec<&str> = vec![];
+    let (_id,): (u32,) = proxy
+        .method_call("org.freedesktop.Notifications", "Notify", (
+            "mybar",
+            // don't replace an earlier notification
+            0u32,
+            "",
+            summary,
+            body,
+            actions,
+            hints,
+            // the server decides when it goes away
+            -1i32,
+        ))
+        .map_err(notify_error)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "dbus"))]
+pub fn send(_summary: &str, _body: &str, _urgency: Urgency) -> error::Result<()> {
+    Err(Error::Notify("built without the dbus feature".to_owned()))
+}
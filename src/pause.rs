@@ -0,0 +1,63 @@
+extern crate signal_hook;
+
+use std::thread;
+use std::sync::{Arc,Mutex,Condvar};
+use signal_hook::iterator::Signals;
+use crate::error::{self,Error};
+
+// i3bar sends these to us when the bar gets hidden and shown again. We pick
+// the signals ourselves and announce them in the protocol header, the
+// default SIGSTOP/SIGCONT would freeze the whole process.
+pub const STOP_SIGNAL: i32 = signal_hook::SIGUSR1;
+pub const CONT_SIGNAL: i32 = signal_hook::SIGUSR2;
+
+/// Shared flag telling every thread of the bar whether it should be idle.
+#[derive(Clone)]
+pub struct Pause {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Pause {
+    pub fn new() -> error::Result<Self> {
+        let signals = Signals::new([STOP_SIGNAL, CONT_SIGNAL])
+            .map_err(Error::Signal)?;
+        let pause = Pause::unsignaled();
+
+        let handler = pause.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                handler.set(signal == STOP_SIGNAL);
+            }
+        });
+
+        Ok(pause)
+    }
+
+    /// A flag no signal changes, it never pauses on its own.
+    pub fn unsignaled() -> Self {
+        Pause {
+            state: Arc::new((Mutex::new(false), Condvar::new())),
+        }
+    }
+
+    fn set(&self, paused: bool) {
+        let (lock, condvar) = &*self.state;
+        *lock.lock().unwrap() = paused;
+        if !paused {
+            condvar.notify_all();
+        }
+    }
+
+    /// Blocks for as long as the bar is hidden. Returns `true` if it had to
+    /// wait, so the caller knows its data is stale.
+    pub fn wait(&self) -> bool {
+        let (lock, condvar) = &*self.state;
+        let mut paused = lock.lock().unwrap();
+        let mut waited = false;
+        while *paused {
+            waited = true;
+            paused = condvar.wait(paused).unwrap();
+        }
+        waited
+    }
+}
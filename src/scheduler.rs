@@ -0,0 +1,158 @@
+use std::time;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use crate::{error,i3};
+use crate::block::{Block,Item,Style};
+
+/// Everything the main loop wakes up for, besides blocks being due.
+pub enum Event {
+    Click(i3::ClickEvent),
+    /// Something changed that the named block shows.
+    Update(String),
+    Reload,
+}
+
+struct Entry {
+    block: Box<dyn Block>,
+    style: Style,
+    // tells apart blocks of the same kind, i3bar echoes it in clicks
+    instance: String,
+    // logged already, a broken block would flood the log otherwise
+    last_error: Option<String>,
+}
+
+/// Runs every block on its own interval and hands the results to the bar.
+/// Deadlines are kept in a heap, so the bar only wakes up when a block is due.
+/// Blocks failing to update or panicking are shown as errors and retried when
+/// they are due again, only a broken bar is an error here.
+pub struct Scheduler {
+    entries: Vec<Entry>,
+    deadlines: BinaryHeap<Reverse<(time::Instant, usize)>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            entries: vec![],
+            deadlines: BinaryHeap::new(),
+        }
+    }
+
+    /// Blocks show up on the bar in the order they were added, their
+    /// position is their instance. They are due right away.
+    pub fn add(&mut self, block: Box<dyn Block>, style: Style) {
+        let index = self.entries.len();
+        self.deadlines.push(Reverse((time::Instant::now(), index)));
+        self.entries.push(Entry {
+            block,
+            style,
+            instance: index.to_string(),
+            last_error: None,
+        });
+    }
+
+    /// When the next block is due, `None` if there are no blocks at all.
+    pub fn next_deadline(&self) -> Option<time::Instant> {
+        self.deadlines.peek().map(|Reverse((deadline, _))| *deadline)
+    }
+
+    /// Updates every block that is due.
+    pub fn run(&mut self, bar: &mut i3::I3) -> error::Result<()> {
+        let now = time::Instant::now();
+        while let Some(&Reverse((deadline, index))) = self.deadlines.peek() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.pop();
+            let entry = &mut self.entries[index];
+            update(entry, bar)?;
+            let next = entry.block.next_update(time::Instant::now());
+            self.deadlines.push(Reverse((next, index)));
+        }
+        Ok(())
+    }
+
+    /// Updates every block right away and starts their intervals over.
+    pub fn run_all(&mut self, bar: &mut i3::I3) -> error::Result<()> {
+        self.deadlines.clear();
+        for (index, entry) in self.entries.iter_mut().enumerate() {
+            update(entry, bar)?;
+            let next = entry.block.next_update(time::Instant::now());
+            self.deadlines.push(Reverse((next, index)));
+        }
+        Ok(())
+    }
+
+    /// Updates every block with that name right away, they keep their
+    /// deadlines.
+    pub fn update(&mut self, name: &str, bar: &mut i3::I3) -> error::Result<()> {
+        let found = self.entries
+            .iter_mut()
+            .filter(|entry| entry.block.name() == name);
+        for entry in found {
+            update(entry, bar)?;
+        }
+        Ok(())
+    }
+
+    /// Hands a click to the block it was made on and shows the result.
+    pub fn click(
+        &mut self,
+        event: &i3::ClickEvent,
+        bar: &mut i3::I3,
+    ) -> error::Result<()> {
+        let clicked = self.entries
+            .iter_mut()
+            .find(|entry| {
+                entry.block.name() == event.name
+                    && event.instance.as_ref() == Some(&entry.instance)
+            });
+        if let Some(entry) = clicked {
+            let block = &mut entry.block;
+            if let Err(err) = error::catch_panic(|| block.click(event)) {
+                error::log(&err.in_block(&event.name));
+            }
+            update(entry, bar)?;
+        }
+        Ok(())
+    }
+}
+
+fn update(entry: &mut Entry, bar: &mut i3::I3) -> error::Result<()> {
+    let block = &mut entry.block;
+    let mut item = match error::catch_panic(|| block.update()) {
+        Ok(item) => {
+            entry.last_error = None;
+            item
+        },
+        Err(err) => {
+            let name = entry.block.name().to_owned();
+            let err = err.in_block(&name);
+            let message = err.to_string();
+            if entry.last_error.as_ref() != Some(&message) {
+                error::log(&err);
+                entry.last_error = Some(message);
+            }
+            if entry.style.hide_on_error {
+                // i3bar leaves out blocks without text
+                Item { name, ..Default::default() }
+            } else {
+                error_item(name)
+            }
+        },
+    };
+    item.instance = Some(entry.instance.clone());
+    entry.style.apply(&mut item);
+    bar.send(item.to_i3_item())
+}
+
+// Takes the place of a block that failed, the log says why.
+fn error_item(name: String) -> Item {
+    Item {
+        text: format!("{} error", name.to_lowercase()),
+        short_text: Some("error".to_owned()),
+        name,
+        good: Some(false),
+        ..Default::default()
+    }
+}
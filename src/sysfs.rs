@@ -0,0 +1,27 @@
+use std::{fs,io};
+use std::path::Path;
+use std::str::FromStr;
+use crate::error::{self,Error};
+
+/// `None` if the file doesn't exist. Not every driver reports everything,
+/// and devices come and go with their drivers.
+pub fn read(path: &Path) -> error::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(value) => Ok(Some(value.trim().to_owned())),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(Error::read(path, err)),
+    }
+}
+
+pub fn read_attribute(dir: &Path, attribute: &str) -> error::Result<Option<String>> {
+    read(&dir.join(attribute))
+}
+
+pub fn read_number(path: &Path) -> error::Result<Option<f64>> {
+    match read(path)? {
+        Some(value) => f64::from_str(&value)
+            .map(Some)
+            .map_err(|err| Error::parse(path, err)),
+        None => Ok(None),
+    }
+}
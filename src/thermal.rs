@@ -0,0 +1,303 @@
+use std::{fs,io,time};
+use std::collections::HashSet;
+use std::path::{Path,PathBuf};
+use crate::format::{self,Value};
+use crate::error::{self,Error};
+use crate::sysfs::{read_attribute,read_number};
+use crate::block::{Block,Item};
+
+const HWMON: &str = "/sys/class/hwmon";
+const THERMAL: &str = "/sys/class/thermal";
+const CPU: &str = "/sys/devices/system/cpu";
+
+// picked in this order if no sensor was configured
+const PACKAGE_LABELS: [&str; 4] = ["Package id 0", "Tctl", "Tdie", "x86_pkg_temp"];
+
+// A temperature sensor, either a hwmon one or a thermal zone.
+#[derive(Clone,Debug)]
+struct Sensor {
+    /// The hwmon chip, e.g. `coretemp`, empty for thermal zones.
+    chip: String,
+    /// The hwmon label, e.g. `Package id 0`, or the thermal zone's type.
+    label: String,
+    /// Reads in millidegrees Celsius.
+    input: PathBuf,
+    /// Where the hardware starts to protect itself, in degrees Celsius.
+    critical: Option<f64>,
+}
+
+impl Sensor {
+    fn matches(&self, name: &str) -> bool {
+        self.label == name || format!("{}/{}", self.chip, self.label) == name
+    }
+}
+
+/// CPU temperature and frequency. `{temp}` is in degrees Celsius, read from
+/// the sensor labeled `sensor` or else the CPU package. `{avg_freq}` and
+/// `{max_freq}` are in GHz over all cores. The block turns red when the
+/// temperature comes within `margin` degrees of the critical one.
+pub struct ThermalBlock {
+    interval: time::Duration,
+    format: String,
+    sensor: Option<String>,
+    critical: Option<f64>,
+    margin: f64,
+    // found on the first update, looked for again when it stops working
+    found: Option<Sensor>,
+    // what broke while looking for sensors, logged once each
+    logged: HashSet<String>,
+}
+
+impl ThermalBlock {
+    pub fn new(
+        interval: Option<time::Duration>,
+        format: Option<String>,
+        sensor: Option<String>,
+        critical: Option<f64>,
+        margin: f64,
+    ) -> Self {
+        ThermalBlock {
+            interval: interval.unwrap_or(time::Duration::from_secs(5)),
+            format: format.unwrap_or_else(|| {
+                "[{temp}\u{b0}C][ {avg_freq} GHz]".to_owned()
+            }),
+            sensor,
+            critical,
+            margin,
+            found: None,
+            logged: HashSet::new(),
+        }
+    }
+
+    fn sensor(&mut self) -> error::Result<Option<Sensor>> {
+        if self.found.is_none() {
+            let mut broken = vec![];
+            let sensors = discover(&mut broken)?;
+            self.log(broken);
+            self.found = match &self.sensor {
+                Some(name) => {
+                    let sensor = sensors
+                        .into_iter()
+                        .find(|sensor| sensor.matches(name))
+                        .ok_or_else(|| Error::parse(
+                            HWMON,
+                            format!("no sensor labeled {}", name),
+                        ))?;
+                    Some(sensor)
+                },
+                None => pick_package(sensors),
+            };
+        }
+        Ok(self.found.clone())
+    }
+
+    fn log(&mut self, broken: Vec<Error>) {
+        for err in broken {
+            let err = err.in_block(self.name());
+            if self.logged.insert(err.to_string()) {
+                error::log(&err);
+            }
+        }
+    }
+}
+
+impl Block for ThermalBlock {
+    fn name(&self) -> &str { "Thermal" }
+
+    fn interval(&self) -> time::Duration { self.interval }
+
+    fn update(&mut self) -> error::Result<Item> {
+        let sensor = self.sensor()?;
+        let temp = match &sensor {
+            Some(sensor) => {
+                let temp = read_number(&sensor.input);
+                if !matches!(temp, Ok(Some(_))) {
+                    // the sensor went away, e.g. a module was reloaded
+                    self.found = None;
+                }
+                temp?.map(|temp| temp / 1000.0)
+            },
+            None => None,
+        };
+        let mut broken = vec![];
+        let freqs = read_frequencies(&mut broken)?;
+        self.log(broken);
+        if temp.is_none() && freqs.is_empty() {
+            // nothing to show, e.g. in a virtual machine
+            return Ok(Item {
+                name: self.name().to_owned(),
+                ..Default::default()
+            });
+        }
+
+        let critical = self.critical
+            .or_else(|| sensor.as_ref().and_then(|sensor| sensor.critical));
+        let good = match (temp, critical) {
+            (Some(temp), Some(critical)) if temp >= critical - self.margin => {
+                Some(false)
+            },
+            _ => None,
+        };
+        let ghz = |khz: f64| Value::number(khz / 1_000_000.0, 1);
+        let avg_freq = if freqs.is_empty() {
+            Value::Missing
+        } else {
+            ghz(freqs.iter().sum::<f64>() / freqs.len() as f64)
+        };
+        let max_freq = freqs.iter().cloned().reduce(f64::max).map(ghz);
+
+        Ok(Item {
+            name: self.name().to_owned(),
+            text: format::render(&self.format, &[
+                ("temp", temp.map(Value::whole).into()),
+                ("avg_freq", avg_freq),
+                ("max_freq", max_freq.into()),
+            ]),
+            good,
+            ..Default::default()
+        })
+    }
+}
+
+fn pick_package(sensors: Vec<Sensor>) -> Option<Sensor> {
+    let package = PACKAGE_LABELS
+        .iter()
+        .find_map(|label| sensors.iter().find(|sensor| sensor.label == *label))
+        .cloned();
+    package.or_else(|| sensors.into_iter().next())
+}
+
+// Every hwmon sensor, then every thermal zone. Sensors that fail to read
+// are left out and their errors put into `broken`, a broken ACPI zone
+// shouldn't hide the CPU package.
+fn discover(broken: &mut Vec<Error>) -> error::Result<Vec<Sensor>> {
+    let mut sensors = vec![];
+    for chip_path in list(HWMON)? {
+        let chip = skip(read_attribute(&chip_path, "name"), broken)
+            .flatten()
+            .unwrap_or_default();
+        let inputs = match skip(list(&chip_path), broken) {
+            Some(inputs) => inputs,
+            None => continue,
+        };
+        for input in inputs {
+            let file_name = match input.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            if !file_name.starts_with("temp") || !file_name.ends_with("_input") {
+                continue;
+            }
+            let prefix = file_name.trim_end_matches("_input");
+            let label = read_attribute(&chip_path, &format!("{}_label", prefix));
+            let label = match skip(label, broken) {
+                Some(label) => label.unwrap_or_else(|| prefix.to_owned()),
+                None => continue,
+            };
+            // a sensor without a known limit is still a sensor
+            let mut limit = |attribute: &str| {
+                let path = chip_path.join(format!("{}_{}", prefix, attribute));
+                skip(read_number(&path), broken).flatten()
+            };
+            let critical = limit("crit").or_else(|| limit("max"));
+            sensors.push(Sensor {
+                chip: chip.clone(),
+                label,
+                input,
+                critical: critical.map(|critical| critical / 1000.0),
+            });
+        }
+    }
+
+    for zone in list(THERMAL)? {
+        let is_zone = zone.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("thermal_zone"));
+        if !is_zone {
+            continue;
+        }
+        let label = match skip(read_attribute(&zone, "type"), broken) {
+            Some(label) => label.unwrap_or_default(),
+            None => continue,
+        };
+        sensors.push(Sensor {
+            chip: String::new(),
+            label,
+            input: zone.join("temp"),
+            critical: zone_critical(&zone, broken),
+        });
+    }
+    Ok(sensors)
+}
+
+// The temperature of the zone's critical trip point. Trips that fail to read
+// are skipped.
+fn zone_critical(zone: &Path, broken: &mut Vec<Error>) -> Option<f64> {
+    for trip in 0.. {
+        let kind = read_attribute(zone, &format!("trip_point_{}_type", trip));
+        let kind = match kind {
+            Ok(Some(kind)) => kind,
+            Ok(None) => return None,
+            Err(err) => {
+                broken.push(err);
+                continue;
+            },
+        };
+        if kind == "critical" {
+            let path = zone.join(format!("trip_point_{}_temp", trip));
+            if let Some(temp) = skip(read_number(&path), broken).flatten() {
+                return Some(temp / 1000.0);
+            }
+        }
+    }
+    None
+}
+
+// `None` if reading failed, the error goes to `broken`.
+fn skip<T>(result: error::Result<T>, broken: &mut Vec<Error>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(err) => {
+            broken.push(err);
+            None
+        },
+    }
+}
+
+// In kHz, for every core that reports it. Cores that fail to read are left
+// out like broken sensors.
+fn read_frequencies(broken: &mut Vec<Error>) -> error::Result<Vec<f64>> {
+    let mut freqs = vec![];
+    for cpu in list(CPU)? {
+        let is_core = cpu.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("cpu"))
+            .is_some_and(|number| number.parse::<u32>().is_ok());
+        if !is_core {
+            continue;
+        }
+        let freq = read_number(&cpu.join("cpufreq/scaling_cur_freq"));
+        if let Some(freq) = skip(freq, broken).flatten() {
+            freqs.push(freq);
+        }
+    }
+    Ok(freqs)
+}
+
+// Sorted, so `hwmon2` keeps coming after `hwmon1`. Empty if `dir` is missing.
+fn list<P: AsRef<Path>>(dir: P) -> error::Result<Vec<PathBuf>> {
+    let dir = dir.as_ref();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(vec![]);
+        },
+        Err(err) => return Err(Error::read(dir, err)),
+    };
+    let mut paths = entries
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|err| Error::read(dir, err))?;
+    paths.sort();
+    Ok(paths)
+}
@@ -0,0 +1,192 @@
+use crate::format::Value;
+
+/// Whether amounts scale by 1000 (kB, MB) or by 1024 (KiB, MiB).
+#[derive(serde::Deserialize,Clone,Copy,Debug,Default,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Prefix {
+    #[default]
+    Si,
+    Iec,
+}
+
+const SI: [&str; 7] = ["", "k", "M", "G", "T", "P", "E"];
+const IEC: [&str; 7] = ["", "Ki", "Mi", "Gi", "Ti", "Pi", "Ei"];
+
+/// How a block shows amounts of bytes and rates.
+#[derive(Clone,Copy,Debug)]
+pub struct Units {
+    pub prefix: Prefix,
+    /// Digits after the point, amounts below the first prefix have none.
+    pub precision: usize,
+    /// Pads every amount to the same width, so the bar doesn't jitter.
+    pub fixed_width: bool,
+    /// Shows rates in bits per second instead of bytes.
+    pub bits: bool,
+}
+
+impl Default for Units {
+    fn default() -> Self {
+        Units {
+            prefix: Prefix::Si,
+            precision: 1,
+            fixed_width: false,
+            bits: false,
+        }
+    }
+}
+
+/// What a block's config says about its units, anything left out keeps the
+/// block's default.
+///
+/// ```toml
+/// [[block]]
+/// type = "traffic"
+/// units = { prefix = "iec", precision = 0, bits = true }
+/// ```
+#[derive(serde::Deserialize,Clone,Copy,Debug,Default)]
+#[serde(deny_unknown_fields)]
+pub struct UnitsConfig {
+    pub prefix: Option<Prefix>,
+    pub precision: Option<usize>,
+    pub fixed_width: Option<bool>,
+    pub bits: Option<bool>,
+}
+
+impl Units {
+    pub fn with(self, config: UnitsConfig) -> Self {
+        Units {
+            prefix: config.prefix.unwrap_or(self.prefix),
+            precision: config.precision.unwrap_or(self.precision),
+            fixed_width: config.fixed_width.unwrap_or(self.fixed_width),
+            bits: config.bits.unwrap_or(self.bits),
+        }
+    }
+
+    /// `3.2 GiB`, `512 B`.
+    pub fn bytes(&self, bytes: f64) -> Value {
+        self.scaled(bytes, "B")
+    }
+
+    /// `1.3 MB/s`, or `10.4 Mbit/s` if the block wants bits.
+    pub fn rate(&self, bytes_per_second: f64) -> Value {
+        if self.bits {
+            self.scaled(bytes_per_second * 8.0, "bit/s")
+        } else {
+            self.scaled(bytes_per_second, "B/s")
+        }
+    }
+
+    /// A rate as wide as any other, e.g. `999.0 KiB/s`, to size a block by.
+    pub fn widest_rate(&self) -> Value {
+        let base = match self.prefix {
+            Prefix::Si => 1000.0,
+            Prefix::Iec => 1024.0,
+        };
+        let bytes_per_second = if self.bits { 999.0 * base / 8.0 } else { 999.0 * base };
+        self.rate(bytes_per_second)
+    }
+
+    fn scaled(&self, value: f64, unit: &str) -> Value {
+        let (base, prefixes) = match self.prefix {
+            Prefix::Si => (1000.0, &SI),
+            Prefix::Iec => (1024.0, &IEC),
+        };
+        // at most 3 digits before the point, so 1000 KiB become 0.98 MiB,
+        // counting what rounds up to 1000 with the digits shown
+        let precision = |power| if power == 0 { 0 } else { self.precision };
+        let limit = |power| 1000.0 - 0.5 / 10f64.powi(precision(power) as i32);
+        let mut value = value;
+        let mut power = 0;
+        while value.abs() >= limit(power) && power + 1 < prefixes.len() {
+            value /= base;
+            power += 1;
+        }
+        let precision = precision(power);
+        let unit = format!("{}{}", prefixes[power], unit);
+
+        if self.fixed_width {
+            let width = match self.precision {
+                0 => 3,
+                precision => 4 + precision,
+            };
+            let unit_width = prefixes
+                .iter()
+                .map(|prefix| prefix.len())
+                .max()
+                .unwrap_or(0) + unit.len() - prefixes[power].len();
+            let unit = format!("{:<unit_width$}", unit, unit_width = unit_width);
+            Value::Amount { value, precision, width, unit }
+        } else {
+            Value::Amount { value, precision, width: 0, unit }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format;
+
+    fn text(value: Value) -> String {
+        format::render("{value}", &[("value", value)])
+    }
+
+    fn iec() -> Units {
+        Units { prefix: Prefix::Iec, ..Units::default() }
+    }
+
+    #[test]
+    fn no_digits_below_the_first_prefix() {
+        assert_eq!(text(Units::default().bytes(512.0)), "512 B");
+        assert_eq!(text(Units::default().bytes(999.0)), "999 B");
+    }
+
+    #[test]
+    fn rolls_over_before_rounding_to_1000() {
+        let units = Units::default();
+        assert_eq!(text(units.bytes(999_940.0)), "999.9 kB");
+        assert_eq!(text(units.bytes(999_960.0)), "1.0 MB");
+        assert_eq!(text(units.bytes(999.6)), "1.0 kB");
+
+        // with fewer digits the cut-off moves
+        let units = Units { precision: 0, ..Units::default() };
+        assert_eq!(text(units.bytes(999_400.0)), "999 kB");
+        assert_eq!(text(units.bytes(999_600.0)), "1 MB");
+    }
+
+    #[test]
+    fn si_and_iec() {
+        let bytes = 1.5 * 1024.0 * 1024.0;
+        assert_eq!(text(Units::default().bytes(bytes)), "1.6 MB");
+        assert_eq!(text(iec().bytes(bytes)), "1.5 MiB");
+        assert_eq!(text(iec().bytes(1000.0)), "1.0 KiB");
+        assert_eq!(text(iec().bytes(1023.0 * 1024.0)), "1.0 MiB");
+    }
+
+    #[test]
+    fn bits() {
+        let units = Units { bits: true, ..Units::default() };
+        assert_eq!(text(units.rate(1000.0)), "8.0 kbit/s");
+        assert_eq!(text(Units::default().rate(1000.0)), "1.0 kB/s");
+    }
+
+    #[test]
+    fn fixed_width() {
+        let units = Units { fixed_width: true, ..Units::default() };
+        assert_eq!(text(units.bytes(512.0)), "  512 B ");
+        assert_eq!(text(units.bytes(1_500_000.0)), "  1.5 MB");
+        assert_eq!(text(units.rate(42_000.0)), " 42.0 kB/s");
+
+        let units = Units { fixed_width: true, ..iec() };
+        assert_eq!(text(units.bytes(512.0)), "  512 B  ");
+        assert_eq!(text(units.bytes(1024.0)), "  1.0 KiB");
+    }
+
+    #[test]
+    fn widest_rate() {
+        assert_eq!(text(Units::default().widest_rate()), "999.0 kB/s");
+        assert_eq!(text(iec().widest_rate()), "999.0 KiB/s");
+        let units = Units { bits: true, precision: 0, ..Units::default() };
+        assert_eq!(text(units.widest_rate()), "999 kbit/s");
+    }
+}